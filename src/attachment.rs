@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use ed25519_dalek::PublicKey;
+use failure::Error;
+use sha3::{Digest, Sha3_256};
+use uuid::Uuid;
+
+use crate::message::{Content, NewOutboundMessage};
+
+pub const MAX_SIZE: u64 = 64 * 1024 * 1024;
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+/// How many attachments a peer may have on their way to us at once.
+pub const MAX_INCOMPLETE: i64 = 4;
+// room for the envelope around a chunk, so a full chunk still pads to CHUNK_SIZE and fits
+// under the peer's max message size
+const CHUNK_OVERHEAD: u64 = 1024;
+const RETRIES: u32 = 5;
+
+pub async fn send(
+    tracking_id: Option<Uuid>,
+    to: PublicKey,
+    reply_to: Option<Uuid>,
+    name: String,
+    data: Vec<u8>,
+) -> Result<(), Error> {
+    if data.len() as u64 > MAX_SIZE {
+        failure::bail!("Attachment too large");
+    }
+    let caps = crate::message::capabilities(&to, false).await?;
+    if !caps.has_feature("attachments") {
+        failure::bail!("Peer does not support attachments");
+    }
+    let ttl = crate::db::get_ttl(to).await?;
+    if ttl.is_some() && !caps.has_feature("disappearing") {
+        return Err(crate::message::NoDisappearing.into());
+    }
+    let mut hash = [0; 32];
+    hash.clone_from_slice(&Sha3_256::digest(&data));
+    let msg = NewOutboundMessage {
+        tracking_id,
+        to,
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to,
+        group: None,
+        time: crate::util::now(),
+        ttl,
+        content: Content::Attachment {
+            name,
+            size: data.len() as u64,
+            hash,
+        },
+    };
+    let id = crate::db::save_out_attachment(msg.clone(), data).await?;
+    crate::message::attempt(id, &msg, 1, msg.time).await?;
+    Ok(())
+}
+
+pub async fn resume(to: PublicKey, id: i64) -> Result<(), Error> {
+    let info = crate::db::get_attachment_info(id)
+        .await?
+        .filter(|info| info.user_id == to)
+        .ok_or_else(|| failure::format_err!("Attachment not found"))?;
+    if info.inbound {
+        failure::bail!("Cannot resume an inbound attachment");
+    }
+    tokio::spawn(async move {
+        if let Err(e) = transfer(info).await {
+            eprintln!("ERROR TRANSFERRING ATTACHMENT {}: {}", id, e);
+        }
+    });
+    Ok(())
+}
+
+// the peer answers every chunk with how much it has, so an interrupted transfer
+// picks up wherever the peer left off rather than where we think it did
+async fn transfer(info: crate::db::AttachmentInfo) -> Result<(), Error> {
+    let chunk_size = crate::message::capabilities(&info.user_id, false)
+        .await?
+        .max_message_size
+        .saturating_sub(CHUNK_OVERHEAD)
+        .clamp(1, CHUNK_SIZE - CHUNK_OVERHEAD);
+    let mut offset = info.received;
+    let mut failures = 0;
+    while !info.complete && offset < info.size {
+        let len = chunk_size.min(info.size - offset);
+        let res = async {
+            let data = crate::db::read_attachment(info.id, offset, len).await?;
+            let body = crate::message::exchange(&NewOutboundMessage {
+                tracking_id: None,
+                to: info.user_id,
+                nonce: rand::random(),
+                uuid: Uuid::new_v4(),
+                reply_to: None,
+                group: None,
+                time: crate::util::now(),
+                ttl: None,
+                content: Content::Chunk {
+                    attachment: info.nonce,
+                    offset,
+                    data,
+                },
+            })
+            .await?;
+            let mut received = [0; 8];
+            received.clone_from_slice(
+                body.get(..8)
+                    .ok_or_else(|| failure::format_err!("Invalid chunk response"))?,
+            );
+            Ok::<_, Error>(u64::from_be_bytes(received))
+        }
+        .await;
+        match res {
+            Ok(received) if received != offset => {
+                failures = 0;
+                offset = received.min(info.size);
+                crate::db::set_attachment_progress(info.id, offset, offset == info.size).await?;
+            }
+            res => {
+                failures += 1;
+                if failures >= RETRIES {
+                    res?;
+                    failure::bail!("Peer is not accepting chunks");
+                }
+                tokio::time::sleep(Duration::from_secs(1 << failures)).await;
+            }
+        }
+    }
+    Ok(())
+}
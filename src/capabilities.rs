@@ -0,0 +1,161 @@
+use failure::Error;
+
+pub const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+pub const FEATURES: &[&str] = &[
+    "receipts",
+    "read-receipts",
+    "presence",
+    "attachments",
+    "edits",
+    "reactions",
+    "groups",
+    "profiles",
+    "disappearing",
+];
+
+// cached capabilities older than this are refetched before the next send
+pub const TTL: i64 = 60 * 60 * 24;
+
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub versions: Vec<u8>,
+    pub max_message_size: u64,
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn local() -> Self {
+        Capabilities {
+            versions: crate::wire::VERSIONS
+                .iter()
+                .copied()
+                .filter(|v| *v != 2 || crate::CONFIG.sessions && !crate::transport::is_loopback())
+                .collect(),
+            max_message_size: crate::CONFIG.max_peer_body,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    // peers predating capability negotiation only send VERSION
+    pub fn legacy() -> Self {
+        Capabilities {
+            versions: vec![0],
+            max_message_size: u64::MAX,
+            features: Vec::new(),
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn best_version(&self) -> Result<u8, Error> {
+        let local = Capabilities::local();
+        self.versions
+            .iter()
+            .filter(|v| local.versions.contains(v))
+            .max()
+            .copied()
+            .ok_or_else(|| failure::format_err!("No mutually supported wire version"))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.push(self.versions.len() as u8);
+        res.extend_from_slice(&self.versions);
+        res.extend_from_slice(&u64::to_be_bytes(self.max_message_size));
+        res.push(self.features.len() as u8);
+        for feature in &self.features {
+            res.push(feature.len() as u8);
+            res.extend_from_slice(feature.as_bytes());
+        }
+        res
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+            if bytes.len() < len {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let (res, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Ok(res)
+        }
+
+        if bytes.is_empty() {
+            return Ok(Capabilities::legacy());
+        }
+        let mut bytes = bytes;
+        let count = take(&mut bytes, 1)?[0] as usize;
+        let versions = take(&mut bytes, count)?.to_vec();
+        let mut size_buf = [0; 8];
+        size_buf.clone_from_slice(take(&mut bytes, 8)?);
+        let count = take(&mut bytes, 1)?[0] as usize;
+        let mut features = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = take(&mut bytes, 1)?[0] as usize;
+            features.push(String::from_utf8(take(&mut bytes, len)?.to_vec())?);
+        }
+        Ok(Capabilities {
+            versions,
+            max_message_size: u64::from_be_bytes(size_buf),
+            features,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(versions: &[u8], features: &[&str]) -> Capabilities {
+        Capabilities {
+            versions: versions.to_vec(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let local = Capabilities::local();
+        let parsed = Capabilities::parse(&local.encode()).unwrap();
+        assert_eq!(parsed.versions, local.versions);
+        assert_eq!(parsed.max_message_size, local.max_message_size);
+        assert_eq!(parsed.features, local.features);
+        let encoded = local.encode();
+        for len in 1..encoded.len() {
+            assert!(Capabilities::parse(&encoded[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn negotiates_highest_shared_version() {
+        // sessions are off under test, so we speak 0 and 1
+        assert_eq!(Capabilities::local().versions, vec![0, 1]);
+        assert_eq!(peer(&[0, 1, 2], &[]).best_version().unwrap(), 1);
+        assert_eq!(peer(&[2, 1, 0], &[]).best_version().unwrap(), 1);
+        assert_eq!(peer(&[0, 9], &[]).best_version().unwrap(), 0);
+        assert!(peer(&[9], &[]).best_version().is_err());
+        assert!(peer(&[], &[]).best_version().is_err());
+    }
+
+    #[test]
+    fn legacy_peers_fall_back_to_v0() {
+        let legacy = Capabilities::parse(&[]).unwrap();
+        assert_eq!(legacy.versions, vec![0]);
+        assert_eq!(legacy.best_version().unwrap(), 0);
+        assert!(!legacy.has_feature("receipts"));
+    }
+
+    #[test]
+    fn ignores_unknown_features() {
+        let caps = Capabilities::parse(
+            &peer(&[0, 1], &["teleport", "receipts", "", "groups-v9"]).encode(),
+        )
+        .unwrap();
+        assert!(caps.has_feature("receipts"));
+        assert!(!caps.has_feature("groups"));
+        assert_eq!(caps.best_version().unwrap(), 1);
+    }
+}
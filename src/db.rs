@@ -1,5 +1,10 @@
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
 use ed25519_dalek::PublicKey;
 use failure::Error;
+use std::io::{Seek, SeekFrom, Write};
+
 use rusqlite::params;
 use rusqlite::Connection;
 use rusqlite::OptionalExtension;
@@ -8,10 +13,14 @@ use failure::ResultExt;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::OpenFlags;
+use sha3::{Digest, Sha3_256};
 
-use crate::message::{NewInboundMessage, NewOutboundMessage};
+use crate::capabilities::Capabilities;
+use crate::group::GroupOp;
+use crate::message::{Content, NewInboundMessage, NewOutboundMessage};
 use crate::query::BeforeAfter;
 use crate::query::Limits;
+use crate::session::Session;
 
 lazy_static::lazy_static! {
     pub static ref POOL: Pool<SqliteConnectionManager> = {
@@ -20,10 +29,32 @@ lazy_static::lazy_static! {
         flags.insert(OpenFlags::SQLITE_OPEN_CREATE);
         flags.insert(OpenFlags::SQLITE_OPEN_FULL_MUTEX);
         flags.insert(OpenFlags::SQLITE_OPEN_PRIVATE_CACHE);
-        Pool::new(SqliteConnectionManager::file("messages.db").with_flags(flags).with_init(|c| c.execute_batch("PRAGMA busy_timeout = 10000;"))).expect("sqlite connection")
+        Pool::new(SqliteConnectionManager::file(path()).with_flags(flags).with_init(|c| c.execute_batch("PRAGMA busy_timeout = 10000;"))).expect("sqlite connection")
     };
 }
 
+// tests get a database of their own rather than the service's
+fn path() -> std::path::PathBuf {
+    if cfg!(test) {
+        std::env::temp_dir().join(format!("cups-test-{}.db", std::process::id()))
+    } else {
+        "messages.db".into()
+    }
+}
+
+// tests share the database, so only the first to get here migrates it
+#[cfg(test)]
+pub async fn migrated() {
+    lazy_static::lazy_static! {
+        static ref MIGRATED: tokio::sync::Mutex<bool> = tokio::sync::Mutex::new(false);
+    }
+    let mut migrated = MIGRATED.lock().await;
+    if !*migrated {
+        crate::migrations::migrate().await.expect("migrate");
+        *migrated = true;
+    }
+}
+
 pub fn cached_exec<P>(conn: &Connection, q: &str, params: P) -> Result<(), Error>
 where
     P: IntoIterator + rusqlite::Params,
@@ -56,291 +87,2244 @@ where
     res.map(|r| r.with_context(|e| format!("{}: {}", q, e)).map_err(From::from)).collect()
 }
 
-pub async fn save_in_message(message: NewInboundMessage) -> Result<(), Error> {
+fn check_nonce(
+    conn: &Connection,
+    from: &PublicKey,
+    nonce: &[u8; 16],
+    time: i64,
+    horizon: i64,
+) -> Result<(), Error> {
+    cached_exec(
+        conn,
+        "DELETE FROM nonces WHERE time < ?1",
+        params![horizon],
+    )?;
+    if cached_query_row(
+        conn,
+        "SELECT 1 FROM nonces WHERE user_id = ?1 AND nonce = ?2",
+        params![&from.as_bytes()[..], &nonce[..]],
+        |_| Ok(()),
+    )?
+    .is_some()
+    {
+        failure::bail!("Replayed message");
+    }
+    cached_exec(
+        conn,
+        "INSERT INTO nonces (user_id, nonce, time) VALUES (?1, ?2, ?3)",
+        params![&from.as_bytes()[..], &nonce[..], time],
+    )?;
+    Ok(())
+}
+
+pub async fn save_in_message(message: NewInboundMessage, horizon: i64) -> Result<(), Error> {
+    let content = match &message.content {
+        Content::Text(text) => text.clone(),
+        _ => failure::bail!("not a text message"),
+    };
     tokio::task::spawn_blocking(move || {
-        let conn = POOL.get()?;
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &message.from, &message.nonce, message.time, horizon)?;
+        if let Some(group) = &message.group {
+            if !is_group_member(&conn, group, &message.from)? {
+                failure::bail!("Not a group member");
+            }
+        }
         cached_exec(
-            &*conn, 
-            "INSERT INTO messages (user_id, inbound, time, content) VALUES (?1, true, ?2, ?3)",
+            &conn, 
+            "INSERT INTO messages (user_id, inbound, time, content, status, nonce, uuid, reply_to, content_type, group_id, received_at, expires_at) VALUES (?1, true, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
             params![
                 &message.from.as_bytes()[..],
                 message.time,
-                message.content
+                content,
+                MessageStatus::Delivered,
+                &message.nonce[..],
+                message.uuid,
+                message.reply_to,
+                message.content.kind(),
+                message.group,
+                crate::util::now(),
+                expires_at(message.time, message.ttl)
             ],
         )?;
+        conn.commit()?;
         Ok::<_, Error>(())
     })
     .await??;
     Ok(())
 }
 
-pub async fn save_out_message(message: NewOutboundMessage) -> Result<(), Error> {
+pub async fn save_read_receipt(
+    from: PublicKey,
+    nonce: [u8; 16],
+    time: i64,
+    nonces: Vec<[u8; 16]>,
+    horizon: i64,
+) -> Result<(), Error> {
     tokio::task::spawn_blocking(move || {
-        let conn = POOL.get()?;
-        cached_exec(
-            &*conn, 
-            "INSERT INTO messages (tracking_id, user_id, inbound, time, content, read) VALUES (?1, ?2, false, ?3, ?4, true)",
-            params![message.tracking_id, &message.to.as_bytes()[..], message.time, message.content],
-        )?;
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        for read in nonces {
+            cached_exec(
+                &conn,
+                "UPDATE messages SET read_at = ?3 WHERE user_id = ?1 AND nonce = ?2 AND NOT inbound AND read_at IS NULL",
+                params![&from.as_bytes()[..], &read[..], time],
+            )?;
+        }
+        conn.commit()?;
         Ok::<_, Error>(())
     })
     .await??;
     Ok(())
 }
 
-pub async fn save_user(pubkey: PublicKey, name: String) -> Result<(), Error> {
+// kinds from newer peers are only kept when their sender offered a fallback text to show
+pub async fn save_unsupported(
+    from: PublicKey,
+    nonce: [u8; 16],
+    uuid: Uuid,
+    time: i64,
+    kind: u8,
+    fallback: Option<String>,
+    horizon: i64,
+) -> Result<(), Error> {
     tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        if let Some(fallback) = fallback {
+            cached_exec(
+                &conn,
+                "INSERT INTO messages (user_id, inbound, time, content, status, nonce, uuid, content_type, received_at) VALUES (?1, true, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![&from.as_bytes()[..], time, fallback, MessageStatus::Delivered, &nonce[..], uuid, kind, crate::util::now()],
+            )?;
+        }
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn save_out_message(message: NewOutboundMessage) -> Result<i64, Error> {
+    let content = match &message.content {
+        Content::Text(text) => text.clone(),
+        _ => failure::bail!("not a text message"),
+    };
+    let res = tokio::task::spawn_blocking(move || {
         let conn = POOL.get()?;
         cached_exec(
-            &*conn,
-            "INSERT INTO users (id, name) VALUES (?1, ?2) ON CONFLICT(id) DO UPDATE SET name = excluded.name",
-            params![&pubkey.as_bytes()[..], name],
+            &conn, 
+            "INSERT INTO messages (tracking_id, user_id, inbound, time, content, read, status, nonce, uuid, reply_to, content_type, attempts, queued_at, expires_at) VALUES (?1, ?2, false, ?3, ?4, true, ?5, ?6, ?7, ?8, ?9, 1, ?3, ?10)",
+            params![message.tracking_id, &message.to.as_bytes()[..], message.time, content, MessageStatus::Pending, &message.nonce[..], message.uuid, message.reply_to, message.content.kind(), expires_at(message.time, message.ttl)],
+        )?;
+        Ok::<_, Error>(conn.last_insert_rowid())
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_in_attachment(message: NewInboundMessage, horizon: i64) -> Result<(), Error> {
+    let (name, size, hash) = match &message.content {
+        Content::Attachment { name, size, hash } => (name.clone(), *size, *hash),
+        _ => failure::bail!("not an attachment"),
+    };
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &message.from, &message.nonce, message.time, horizon)?;
+        // each incomplete attachment reserves its full size up front, so a peer only gets a few at a time
+        let incomplete: i64 = cached_query_row(
+            &conn,
+            "SELECT COUNT(*) FROM attachments WHERE user_id = ?1 AND inbound AND NOT complete",
+            params![&message.from.as_bytes()[..]],
+            |row| row.get(0),
+        )?
+        .unwrap_or(0);
+        if size > 0 && incomplete >= crate::attachment::MAX_INCOMPLETE {
+            failure::bail!("Too many incomplete attachments");
+        }
+        cached_exec(
+            &conn,
+            "INSERT INTO messages (user_id, inbound, time, content, status, nonce, uuid, reply_to, content_type, received_at, expires_at) VALUES (?1, true, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                &message.from.as_bytes()[..],
+                message.time,
+                name,
+                MessageStatus::Delivered,
+                &message.nonce[..],
+                message.uuid,
+                message.reply_to,
+                message.content.kind(),
+                crate::util::now(),
+                expires_at(message.time, message.ttl)
+            ],
+        )?;
+        cached_exec(
+            &conn,
+            "INSERT INTO attachments (message_id, user_id, nonce, inbound, name, hash, size, complete, data) VALUES (?1, ?2, ?3, true, ?4, ?5, ?6, ?7, zeroblob(?6))",
+            params![
+                conn.last_insert_rowid(),
+                &message.from.as_bytes()[..],
+                &message.nonce[..],
+                name,
+                &hash[..],
+                size as i64,
+                size == 0
+            ],
         )?;
+        conn.commit()?;
         Ok::<_, Error>(())
     })
     .await??;
     Ok(())
 }
 
-pub async fn del_user(pubkey: PublicKey) -> Result<(), Error> {
+pub async fn save_out_attachment(message: NewOutboundMessage, data: Vec<u8>) -> Result<i64, Error> {
+    let (name, hash) = match &message.content {
+        Content::Attachment { name, hash, .. } => (name.clone(), *hash),
+        _ => failure::bail!("not an attachment"),
+    };
     let res = tokio::task::spawn_blocking(move || {
         let mut gconn = POOL.get()?;
         let conn = gconn.transaction()?;
         cached_exec(
-            &*conn,
-            "DELETE FROM users WHERE id = ?1",
-            params![&pubkey.as_bytes()[..]],
+            &conn,
+            "INSERT INTO messages (tracking_id, user_id, inbound, time, content, read, status, nonce, uuid, reply_to, content_type, attempts, queued_at, expires_at) VALUES (?1, ?2, false, ?3, ?4, true, ?5, ?6, ?7, ?8, ?9, 1, ?3, ?10)",
+            params![message.tracking_id, &message.to.as_bytes()[..], message.time, name, MessageStatus::Pending, &message.nonce[..], message.uuid, message.reply_to, message.content.kind(), expires_at(message.time, message.ttl)],
         )?;
+        let id = conn.last_insert_rowid();
         cached_exec(
-            &*conn,
-            "DELETE FROM messages WHERE user_id = ?1",
-            params![&pubkey.as_bytes()[..]],
+            &conn,
+            "INSERT INTO attachments (message_id, user_id, nonce, inbound, name, hash, size, complete, data) VALUES (?1, ?2, ?3, false, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                id,
+                &message.to.as_bytes()[..],
+                &message.nonce[..],
+                name,
+                &hash[..],
+                data.len() as i64,
+                data.is_empty(),
+                data
+            ],
         )?;
         conn.commit()?;
-        Ok::<_, Error>(())
+        Ok::<_, Error>(id)
+    })
+    .await??;
+    Ok(res)
+}
+
+// writes a chunk if it continues where the last one ended, returning how much of the attachment we now have
+pub async fn save_chunk(
+    from: PublicKey,
+    nonce: [u8; 16],
+    time: i64,
+    attachment: [u8; 16],
+    offset: u64,
+    data: Vec<u8>,
+    horizon: i64,
+) -> Result<u64, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        let (id, size, received, complete): (i64, i64, i64, bool) = cached_query_row(
+            &conn,
+            "SELECT id, size, received, complete FROM attachments WHERE user_id = ?1 AND nonce = ?2 AND inbound",
+            params![&from.as_bytes()[..], &attachment[..]],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?
+        .ok_or_else(|| failure::format_err!("Unknown attachment"))?;
+        let (size, mut received) = (size as u64, received as u64);
+        if !complete && offset == received && !data.is_empty() {
+            if offset + data.len() as u64 > size {
+                failure::bail!("Chunk exceeds attachment size");
+            }
+            let mut blob = conn.blob_open(rusqlite::DatabaseName::Main, "attachments", "data", id, false)?;
+            blob.seek(SeekFrom::Start(offset))?;
+            blob.write_all(&data)?;
+            drop(blob);
+            received += data.len() as u64;
+            let mut complete = false;
+            if received == size {
+                let (hash, data): (Vec<u8>, Vec<u8>) = cached_query_row(
+                    &conn,
+                    "SELECT hash, data FROM attachments WHERE id = ?1",
+                    params![id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?
+                .ok_or_else(|| failure::format_err!("Unknown attachment"))?;
+                if Sha3_256::digest(&data)[..] == hash[..] {
+                    complete = true;
+                } else {
+                    eprintln!("ATTACHMENT {} FAILED HASH VERIFICATION, RESTARTING", id);
+                    received = 0;
+                }
+            }
+            cached_exec(
+                &conn,
+                "UPDATE attachments SET received = ?2, complete = ?3 WHERE id = ?1",
+                params![id, received as i64, complete],
+            )?;
+        }
+        conn.commit()?;
+        Ok::<_, Error>(received)
     })
     .await??;
     Ok(res)
 }
 
 #[derive(Clone, Debug)]
-pub struct UserInfo {
-    pub pubkey: PublicKey,
-    pub name: Option<String>,
-    pub unreads: i64,
+pub struct AttachmentInfo {
+    pub id: i64,
+    pub user_id: PublicKey,
+    pub nonce: [u8; 16],
+    pub inbound: bool,
+    pub size: u64,
+    pub received: u64,
+    pub complete: bool,
 }
 
-pub async fn get_user_info() -> Result<Vec<UserInfo>, Error> {
+pub async fn get_attachment_info(id: i64) -> Result<Option<AttachmentInfo>, Error> {
     let res = tokio::task::spawn_blocking(move || {
         let conn = POOL.get()?;
-        let mut stmt = conn.prepare_cached(
-            "SELECT
-                messages.user_id,
-                users.name,
-                SUM(CASE WHEN messages.read THEN 0 ELSE 1 END)
-            FROM messages
-            LEFT JOIN users
-            ON messages.user_id = users.id
-            GROUP BY messages.user_id, users.name
-            UNION ALL
-            SELECT
-                users.id,
-                users.name,
-                count(messages.id)
-            FROM users
-            LEFT JOIN messages
-            ON messages.user_id = users.id
-            WHERE messages.user_id IS NULL
-            GROUP BY users.id, users.name",
-        )?;
-        let res = stmt
-            .query_map(params![], |row| {
+        let res = cached_query_row(
+            &conn,
+            "SELECT user_id, nonce, inbound, size, received, complete FROM attachments WHERE id = ?1",
+            params![id],
+            |row| {
                 let uid: Vec<u8> = row.get(0)?;
-                Ok(UserInfo {
-                    pubkey: PublicKey::from_bytes(&uid).map_err(|e| {
+                let nonce: Vec<u8> = row.get(1)?;
+                let mut nonce_buf = [0; 16];
+                nonce_buf.clone_from_slice(&nonce);
+                Ok(AttachmentInfo {
+                    id,
+                    user_id: PublicKey::from_bytes(&uid).map_err(|e| {
                         rusqlite::Error::FromSqlConversionFailure(
                             0,
                             rusqlite::types::Type::Blob,
                             Box::new(e),
                         )
                     })?,
-                    name: row.get(1)?,
-                    unreads: row.get(2)?,
+                    nonce: nonce_buf,
+                    inbound: row.get(2)?,
+                    size: row.get::<_, i64>(3)? as u64,
+                    received: row.get::<_, i64>(4)? as u64,
+                    complete: row.get(5)?,
                 })
-            })?
-            .collect::<Result<_, _>>()?;
+            },
+        )?;
         Ok::<_, Error>(res)
     })
     .await??;
     Ok(res)
 }
 
-#[derive(Clone, Debug)]
-pub struct Message {
-    pub id: i64,
-    pub tracking_id: Option<Uuid>,
-    pub time: i64,
-    pub inbound: bool,
-    pub content: String,
+pub async fn get_attachment_id(message_id: i64) -> Result<Option<i64>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT id FROM attachments WHERE message_id = ?1",
+            params![message_id],
+            |row| row.get(0),
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
 }
 
-pub async fn get_messages(
-    pubkey: PublicKey,
-    limits: Limits,
-    mark_as_read: bool,
-) -> Result<Vec<Message>, Error> {
+pub async fn read_attachment(id: i64, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT substr(data, ?2, ?3) FROM attachments WHERE id = ?1",
+            params![id, offset as i64 + 1, len as i64],
+            |row| row.get(0),
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    res.ok_or_else(|| failure::format_err!("Attachment not found"))
+}
+
+pub async fn set_attachment_progress(id: i64, received: u64, complete: bool) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "UPDATE attachments SET received = ?2, complete = ?3 WHERE id = ?1",
+            params![id, received as i64, complete],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn get_attachment(id: i64) -> Result<Option<(bool, bool, Vec<u8>)>, Error> {
     let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT inbound, complete, data FROM attachments WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+fn apply_edit(
+    conn: &Connection,
+    user_id: &PublicKey,
+    inbound: bool,
+    target: Uuid,
+    text: String,
+    time: i64,
+) -> Result<(), Error> {
+    let (id, content): (i64, String) = cached_query_row(
+        conn,
+        "SELECT id, content FROM messages WHERE user_id = ?1 AND uuid = ?2 AND inbound = ?3 AND NOT retracted",
+        params![&user_id.as_bytes()[..], target, inbound],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?
+    .ok_or_else(|| failure::format_err!("Unknown message"))?;
+    cached_exec(
+        conn,
+        "INSERT INTO edits (message_id, content, time) VALUES (?1, ?2, ?3)",
+        params![id, content, time],
+    )?;
+    cached_exec(
+        conn,
+        "UPDATE messages SET content = ?2, edited = true WHERE id = ?1",
+        params![id, text],
+    )?;
+    Ok(())
+}
+
+fn apply_retract(
+    conn: &Connection,
+    user_id: &PublicKey,
+    inbound: bool,
+    target: Uuid,
+) -> Result<(), Error> {
+    let id: i64 = cached_query_row(
+        conn,
+        "SELECT id FROM messages WHERE user_id = ?1 AND uuid = ?2 AND inbound = ?3",
+        params![&user_id.as_bytes()[..], target, inbound],
+        |row| row.get(0),
+    )?
+    .ok_or_else(|| failure::format_err!("Unknown message"))?;
+    cached_exec(
+        conn,
+        "UPDATE messages SET content = '', retracted = true WHERE id = ?1",
+        params![id],
+    )?;
+    cached_exec(conn, "DELETE FROM edits WHERE message_id = ?1", params![id])?;
+    cached_exec(conn, "DELETE FROM reactions WHERE message_id = ?1", params![id])?;
+    cached_exec(conn, "DELETE FROM attachments WHERE message_id = ?1", params![id])?;
+    Ok(())
+}
+
+pub async fn save_edit(
+    from: PublicKey,
+    nonce: [u8; 16],
+    time: i64,
+    target: Uuid,
+    text: String,
+    horizon: i64,
+) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
         let mut gconn = POOL.get()?;
         let conn = gconn.transaction()?;
-        if mark_as_read {
-            match (&limits.before_after, &limits.limit) {
-                (Some(BeforeAfter::Before(before)), None) => cached_exec(
-                    &*conn, 
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND id < ?2 ORDER BY id DESC)",
-                    params![&pubkey.as_bytes()[..], before],
-                )?,
-                (Some(BeforeAfter::Before(before)), Some(limit)) => cached_exec(
-                    &*conn, 
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3)",
-                    params![&pubkey.as_bytes()[..], before, *limit as i64],
-                )?,
-                (Some(BeforeAfter::After(after)), None) => cached_exec(
-                    &*conn,
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND id > ?2 ORDER BY id ASC)",
-                    params![&pubkey.as_bytes()[..], after],
-                )?,
-                (Some(BeforeAfter::After(after)), Some(limit)) => cached_exec(
-                    &*conn,
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND id > ?2 ORDER BY id ASC LIMIT ?3)",
-                    params![&pubkey.as_bytes()[..], after, *limit as i64],
-                )?,
-                (None, None) => cached_exec(
-                    &*conn,
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 ORDER BY id DESC)",
-                    params![&pubkey.as_bytes()[..]],
-                )?,
-                (None, Some(limit)) => cached_exec(
-                    &*conn,
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2)",
-                    params![&pubkey.as_bytes()[..], *limit as i64],
-                )?,
-            };
-        }
-        let mapper = |row: &rusqlite::Row| {
-            Ok(Message {
-                id: row.get(0)?,
-                tracking_id: row.get(1)?,
-                time: row.get(2)?,
-                inbound: row.get(3)?,
-                content: row.get(4)?,
-            })
-        };
-        let res = match (&limits.before_after, &limits.limit) {
-            (Some(BeforeAfter::Before(before)), None) => cached_query_map(
-                &*conn, 
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 AND id < ?2 ORDER BY id DESC",
-                params![&pubkey.as_bytes()[..], before],
-                mapper,
-            )?,
-            (Some(BeforeAfter::Before(before)), Some(limit)) => cached_query_map(
-                &*conn, 
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
-                params![&pubkey.as_bytes()[..], before, *limit as i64],
-                mapper,
-            )?,
-            (Some(BeforeAfter::After(after)), None) => cached_query_map(
-                &*conn,
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 AND id > ?2 ORDER BY id ASC",
-                params![&pubkey.as_bytes()[..], after],
-                mapper,
-            )?,
-            (Some(BeforeAfter::After(after)), Some(limit)) => cached_query_map(
-                &*conn,
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 AND id > ?2 ORDER BY id ASC LIMIT ?3",
-                params![&pubkey.as_bytes()[..], after, *limit as i64],
-                mapper,
-            )?,
-            (None, None) => cached_query_map(
-                &*conn,
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 ORDER BY id DESC",
-                params![&pubkey.as_bytes()[..]],
-                mapper,
-            )?,
-            (None, Some(limit)) => cached_query_map(
-                &*conn,
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2",
-                params![&pubkey.as_bytes()[..], *limit as i64],
-                mapper,
-            )?,
-        };
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        apply_edit(&conn, &from, true, target, text, time)?;
         conn.commit()?;
-        Ok::<_, Error>(res)
+        Ok::<_, Error>(())
     })
     .await??;
-    Ok(res)
+    Ok(())
 }
 
-pub async fn get_new_messages(
-    pubkey: PublicKey,
-    limit: Option<usize>,
-    mark_as_read: bool,
-) -> Result<Vec<Message>, Error> {
-    let res = tokio::task::spawn_blocking(move || {
+pub async fn save_retract(
+    from: PublicKey,
+    nonce: [u8; 16],
+    time: i64,
+    target: Uuid,
+    horizon: i64,
+) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
         let mut gconn = POOL.get()?;
         let conn = gconn.transaction()?;
-        let id: Option<i64> = cached_query_row(
-            &*conn, 
-            "SELECT id FROM messages WHERE user_id = ?1 AND read = false ORDER BY id ASC LIMIT 1",
-            params![&pubkey.as_bytes()[..]],
-            |row| row.get(0),
-        )?;
-        let id = if let Some(id) = id {
-            id
-        } else {
-            return Ok(Vec::new());
-        };
-        if mark_as_read {
-            if let Some(limit) = limit {
-                cached_exec(
-                    &*conn,
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND id >= ?2 ORDER BY id ASC LIMIT ?3)",
-                    params![&pubkey.as_bytes()[..], id, limit as i64]
-                )?;
-            } else {
-                cached_exec(
-                    &*conn,
-                    "UPDATE messages SET read = true WHERE user_id = ?1 AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND id >= ?2 ORDER BY id ASC)",
-                    params![&pubkey.as_bytes()[..], id]
-                )?;
-            }
-        }
-        let mapper = |row: &rusqlite::Row| {
-            Ok(Message {
-                id: row.get(0)?,
-                tracking_id: row.get(1)?,
-                time: row.get(2)?,
-                inbound: row.get(3)?,
-                content: row.get(4)?,
-            })
-        };
-        let res = if let Some(limit) = limit {
-            cached_query_map(
-                &*conn,
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 AND id >= ?2 ORDER BY id ASC LIMIT ?3",
-                params![&pubkey.as_bytes()[..], id, limit as i64],
-                mapper
-            )?
-        } else {
-            cached_query_map(
-                &*conn,
-                "SELECT id, tracking_id, time, inbound, content FROM messages WHERE user_id = ?1 AND id >= ?2 ORDER BY id ASC",
-                params![&pubkey.as_bytes()[..], id],
-                mapper
-            )?
-        };
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        apply_retract(&conn, &from, true, target)?;
         conn.commit()?;
-        Ok::<_, Error>(res)
+        Ok::<_, Error>(())
     })
     .await??;
-    Ok(res)
+    Ok(())
+}
+
+pub async fn edit_out_message(to: PublicKey, target: Uuid, text: String, time: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        apply_edit(&conn, &to, false, target, text, time)?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn retract_out_message(to: PublicKey, target: Uuid) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        apply_retract(&conn, &to, false, target)?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn get_out_message_uuid(to: PublicKey, id: i64) -> Result<Option<Uuid>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT uuid FROM messages WHERE user_id = ?1 AND id = ?2 AND NOT inbound AND NOT retracted",
+            params![&to.as_bytes()[..], id],
+            |row| row.get(0),
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+// an empty emoji removes the reactor's reaction
+fn apply_reaction(
+    conn: &Connection,
+    user_id: &PublicKey,
+    reactor: &PublicKey,
+    target: Uuid,
+    emoji: String,
+    time: i64,
+) -> Result<(), Error> {
+    let id: i64 = cached_query_row(
+        conn,
+        "SELECT id FROM messages WHERE user_id = ?1 AND uuid = ?2 AND NOT retracted",
+        params![&user_id.as_bytes()[..], target],
+        |row| row.get(0),
+    )?
+    .ok_or_else(|| failure::format_err!("Unknown message"))?;
+    if emoji.is_empty() {
+        cached_exec(
+            conn,
+            "DELETE FROM reactions WHERE message_id = ?1 AND reactor = ?2",
+            params![id, &reactor.as_bytes()[..]],
+        )?;
+    } else {
+        cached_exec(
+            conn,
+            "INSERT INTO reactions (message_id, reactor, emoji, time) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(message_id, reactor) DO UPDATE SET emoji = excluded.emoji, time = excluded.time",
+            params![id, &reactor.as_bytes()[..], emoji, time],
+        )?;
+    }
+    Ok(())
+}
+
+pub async fn save_reaction(
+    from: PublicKey,
+    nonce: [u8; 16],
+    time: i64,
+    target: Uuid,
+    emoji: String,
+    horizon: i64,
+) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        apply_reaction(&conn, &from, &from, target, emoji, time)?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn save_out_reaction(to: PublicKey, target: Uuid, emoji: String, time: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        apply_reaction(&conn, &to, &PublicKey::from(&*crate::SECKEY), target, emoji, time)?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn get_message_uuid(pubkey: PublicKey, id: i64) -> Result<Option<Uuid>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT uuid FROM messages WHERE user_id = ?1 AND id = ?2 AND NOT retracted",
+            params![&pubkey.as_bytes()[..], id],
+            |row| row.get(0),
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+#[derive(Clone, Debug)]
+pub struct Edit {
+    pub content: String,
+    pub time: i64,
+}
+
+pub async fn get_edits(id: i64) -> Result<Vec<Edit>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_map(
+            &conn,
+            "SELECT content, time FROM edits WHERE message_id = ?1 ORDER BY id ASC",
+            params![id],
+            |row| {
+                Ok(Edit {
+                    content: row.get(0)?,
+                    time: row.get(1)?,
+                })
+            },
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn set_status(id: i64, status: MessageStatus) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "UPDATE messages SET status = ?2 WHERE id = ?1",
+            params![id, status],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// A pending outbound message still to be retried. For a group message, `message.to` is the
+/// member still to be reached.
+#[derive(Clone)]
+pub struct Queued {
+    pub id: i64,
+    pub message: NewOutboundMessage,
+    pub attempts: u32,
+    pub queued_at: i64,
+}
+
+// only pending text messages and attachments with no attempt in flight are retried; a null
+// `next_attempt_at` marks the attempt in progress
+pub async fn claim_due_messages(now: i64, limit: usize) -> Result<Vec<Queued>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        let due = cached_query_map(
+            &conn,
+            "SELECT m.id, m.tracking_id, m.user_id, m.time, m.content, m.nonce, m.uuid, m.reply_to, m.attempts, m.queued_at, m.status, m.expires_at, m.content_type, a.size, a.hash FROM messages m LEFT JOIN attachments a ON a.message_id = m.id WHERE m.next_attempt_at <= ?1 AND m.status IN (?2, ?3) AND NOT m.inbound ORDER BY m.next_attempt_at LIMIT ?4",
+            params![now, MessageStatus::Pending, MessageStatus::Scheduled, limit as i64],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<Uuid>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                    nonce_mapper_at(row, 5)?,
+                    row.get::<_, Option<Uuid>>(6)?,
+                    row.get::<_, Option<Uuid>>(7)?,
+                    row.get::<_, u32>(8)?,
+                    row.get::<_, i64>(9)?,
+                    row.get::<_, MessageStatus>(10)?,
+                    row.get::<_, Option<i64>>(11)?,
+                    row.get::<_, u8>(12)?,
+                    row.get::<_, Option<i64>>(13)?,
+                    row.get::<_, Option<Vec<u8>>>(14)?,
+                ))
+            },
+        )?;
+        let mut res = Vec::with_capacity(due.len());
+        for (id, tracking_id, to, sent_at, content, nonce, uuid, reply_to, attempts, queued_at, status, expires, kind, size, hash) in due {
+            // a scheduled message is dated when it actually goes out, and its timer starts then
+            let time = if status == MessageStatus::Scheduled { now } else { sent_at };
+            let ttl = expires.map(|expires| (expires - sent_at) as u32);
+            // an attachment's header is retried like a text message; its chunks follow once it is through
+            let content = match (kind, size, hash) {
+                (3, Some(size), Some(hash)) if hash.len() == 32 => {
+                    let mut hash_buf = [0; 32];
+                    hash_buf.clone_from_slice(&hash);
+                    Content::Attachment { name: content, size: size as u64, hash: hash_buf }
+                }
+                _ => Content::Text(content),
+            };
+            cached_exec(
+                &conn,
+                "UPDATE messages SET next_attempt_at = NULL, attempts = attempts + 1, status = ?2, time = ?3, expires_at = ?4 WHERE id = ?1",
+                params![id, MessageStatus::Pending, time, expires_at(time, ttl)],
+            )?;
+            res.push(Queued {
+                id,
+                message: NewOutboundMessage {
+                    tracking_id,
+                    to: PublicKey::from_bytes(&to)?,
+                    nonce: nonce.ok_or_else(|| failure::format_err!("Message {} has no nonce", id))?,
+                    uuid: uuid.unwrap_or_else(Uuid::new_v4),
+                    reply_to,
+                    group: None,
+                    time,
+                    ttl,
+                    content,
+                },
+                attempts: attempts + 1,
+                queued_at,
+            });
+        }
+        conn.commit()?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+// attempts cut short by a restart are picked up again
+pub async fn reset_outbox(now: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "UPDATE messages SET next_attempt_at = ?1 WHERE next_attempt_at IS NULL AND status = ?2 AND NOT inbound AND content_type IN (0, 3) AND group_id IS NULL AND nonce IS NOT NULL",
+            params![now, MessageStatus::Pending],
+        )?;
+        cached_exec(
+            &conn,
+            "UPDATE deliveries SET next_attempt_at = ?1 WHERE next_attempt_at IS NULL AND status = ?2",
+            params![now, MessageStatus::Pending],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Records a failed attempt, giving up on the message if there is no `retry_at`.
+pub async fn save_failed_attempt(id: i64, error: String, retry_at: Option<i64>) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "UPDATE messages SET last_error = ?2, next_attempt_at = ?3, status = CASE WHEN ?3 IS NULL THEN ?4 ELSE status END WHERE id = ?1",
+            params![id, error, retry_at, MessageStatus::Failed],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Stores a message for the outbox to send at `send_at`; until then it is neither dated nor
+/// attempted.
+pub async fn save_scheduled_message(message: NewOutboundMessage, send_at: i64) -> Result<i64, Error> {
+    let content = match &message.content {
+        Content::Text(text) => text.clone(),
+        _ => failure::bail!("not a text message"),
+    };
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO messages (tracking_id, user_id, inbound, time, content, read, status, nonce, uuid, reply_to, content_type, attempts, queued_at, next_attempt_at, expires_at) VALUES (?1, ?2, false, ?3, ?4, true, ?5, ?6, ?7, ?8, ?9, 0, ?3, ?3, ?10)",
+            params![message.tracking_id, &message.to.as_bytes()[..], send_at, content, MessageStatus::Scheduled, &message.nonce[..], message.uuid, message.reply_to, message.content.kind(), expires_at(send_at, message.ttl)],
+        )?;
+        Ok::<_, Error>(conn.last_insert_rowid())
+    })
+    .await??;
+    Ok(res)
+}
+
+#[derive(Clone, Debug)]
+pub struct Scheduled {
+    pub id: i64,
+    pub tracking_id: Option<Uuid>,
+    pub to: PublicKey,
+    pub send_at: i64,
+    pub content: String,
+}
+
+pub async fn get_scheduled() -> Result<Vec<Scheduled>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_query_map(
+            &conn,
+            "SELECT id, tracking_id, user_id, next_attempt_at, content FROM messages WHERE status = ?1 AND NOT inbound ORDER BY next_attempt_at",
+            params![MessageStatus::Scheduled],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, Vec<u8>>(2)?, row.get(3)?, row.get(4)?)),
+        )?
+        .into_iter()
+        .map(|(id, tracking_id, to, send_at, content)| {
+            Ok(Scheduled {
+                id,
+                tracking_id,
+                to: PublicKey::from_bytes(&to)?,
+                send_at,
+                content,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()
+    })
+    .await??;
+    Ok(res)
+}
+
+// only until the outbox has picked it up
+pub async fn del_scheduled(id: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        if cached_query_row(
+            &conn,
+            "SELECT 1 FROM messages WHERE id = ?1 AND status = ?2 AND NOT inbound",
+            params![id, MessageStatus::Scheduled],
+            |_| Ok(()),
+        )?
+        .is_none()
+        {
+            failure::bail!("Scheduled message not found");
+        }
+        cached_exec(&conn, "DELETE FROM messages WHERE id = ?1", params![id])?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+// a resent message gets a fresh retry period but keeps its nonce, so the peer can tell if an
+// earlier attempt did reach it after all
+pub async fn requeue(pubkey: PublicKey, id: i64, now: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        if cached_query_row(
+            &conn,
+            "SELECT 1 FROM messages WHERE user_id = ?1 AND id = ?2 AND status = ?3 AND NOT inbound AND content_type IN (0, 3) AND group_id IS NULL AND nonce IS NOT NULL",
+            params![&pubkey.as_bytes()[..], id, MessageStatus::Failed],
+            |_| Ok(()),
+        )?
+        .is_none()
+        {
+            failure::bail!("Message not found");
+        }
+        cached_exec(
+            &conn,
+            "UPDATE messages SET status = ?2, next_attempt_at = ?3, queued_at = ?3 WHERE id = ?1",
+            params![id, MessageStatus::Pending, now],
+        )?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendState {
+    Queued = 0,
+    Sending = 1,
+    Sent = 2,
+    Delivered = 3,
+    Read = 4,
+    Failed = 5,
+    Scheduled = 6,
+}
+
+#[derive(Clone, Debug)]
+pub struct SendStatus {
+    pub tracking_id: Uuid,
+    pub id: i64,
+    pub state: SendState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+// tracking ids we have no outbound message for are left out
+pub async fn get_send_status(tracking_ids: Vec<Uuid>) -> Result<Vec<SendStatus>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let mut res = Vec::with_capacity(tracking_ids.len());
+        for tracking_id in tracking_ids {
+            let status = cached_query_row(
+                &conn,
+                "SELECT id, status, next_attempt_at IS NOT NULL, read_at IS NOT NULL, attempts, last_error FROM messages WHERE tracking_id = ?1 AND NOT inbound ORDER BY id DESC LIMIT 1",
+                params![tracking_id],
+                |row| {
+                    let status: MessageStatus = row.get(1)?;
+                    Ok(SendStatus {
+                        tracking_id,
+                        id: row.get(0)?,
+                        state: match status {
+                            MessageStatus::Pending if row.get(2)? => SendState::Queued,
+                            MessageStatus::Pending => SendState::Sending,
+                            MessageStatus::Failed => SendState::Failed,
+                            MessageStatus::Scheduled => SendState::Scheduled,
+                            _ if row.get(3)? => SendState::Read,
+                            MessageStatus::Sent => SendState::Sent,
+                            MessageStatus::Delivered => SendState::Delivered,
+                        },
+                        attempts: row.get(4)?,
+                        last_error: row.get(5)?,
+                    })
+                },
+            )?;
+            res.extend(status);
+        }
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_user(pubkey: PublicKey, name: String) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO users (id, name) VALUES (?1, ?2) ON CONFLICT(id) DO UPDATE SET name = excluded.name",
+            params![&pubkey.as_bytes()[..], name],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn set_read_receipts(pubkey: PublicKey, enabled: bool) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO user_settings (user_id, read_receipts) VALUES (?1, ?2) ON CONFLICT(user_id) DO UPDATE SET read_receipts = excluded.read_receipts",
+            params![&pubkey.as_bytes()[..], enabled],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn read_receipts_enabled(pubkey: PublicKey) -> Result<bool, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT read_receipts FROM user_settings WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+            |row| row.get(0),
+        )?;
+        Ok::<_, Error>(res.unwrap_or(true))
+    })
+    .await??;
+    Ok(res)
+}
+
+/// The disappearing message timer for a conversation, `None` when off.
+pub async fn set_ttl(pubkey: PublicKey, ttl: Option<u32>) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO user_settings (user_id, ttl) VALUES (?1, ?2) ON CONFLICT(user_id) DO UPDATE SET ttl = excluded.ttl",
+            params![&pubkey.as_bytes()[..], ttl],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn get_ttl(pubkey: PublicKey) -> Result<Option<u32>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT ttl FROM user_settings WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+            |row| row.get(0),
+        )?;
+        Ok::<_, Error>(res.flatten())
+    })
+    .await??;
+    Ok(res)
+}
+
+fn expires_at(time: i64, ttl: Option<u32>) -> Option<i64> {
+    ttl.map(|ttl| time + ttl as i64)
+}
+
+/// Deletes messages whose timer ran out, along with everything hanging off them.
+pub async fn del_expired(now: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        for q in &[
+            "DELETE FROM edits WHERE message_id IN (SELECT id FROM messages WHERE expires_at <= ?1)",
+            "DELETE FROM reactions WHERE message_id IN (SELECT id FROM messages WHERE expires_at <= ?1)",
+            "DELETE FROM attachments WHERE message_id IN (SELECT id FROM messages WHERE expires_at <= ?1)",
+            "DELETE FROM deliveries WHERE message_id IN (SELECT id FROM messages WHERE expires_at <= ?1)",
+            "DELETE FROM messages WHERE expires_at <= ?1",
+        ] {
+            cached_exec(&conn, q, params![now])?;
+        }
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn del_user(pubkey: PublicKey) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "DELETE FROM users WHERE id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM edits WHERE message_id IN (SELECT id FROM messages WHERE user_id = ?1)",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM reactions WHERE message_id IN (SELECT id FROM messages WHERE user_id = ?1)",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM messages WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM user_settings WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM attachments WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM sessions WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM group_members WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM profiles WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        // group messages still waiting on them are settled by the remaining members
+        let group_messages = cached_query_map(
+            &conn,
+            "SELECT message_id FROM deliveries WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+            |row| row.get::<_, i64>(0),
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM deliveries WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        for id in group_messages {
+            update_group_status(&conn, id)?;
+        }
+        cached_exec(
+            &conn,
+            "DELETE FROM capabilities WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM nonces WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct UserInfo {
+    pub pubkey: PublicKey,
+    pub name: Option<String>,
+    pub unreads: i64,
+    pub profile: Option<Profile>,
+    /// Receive time of the latest message, our own send time for outbound ones.
+    pub last_received: Option<i64>,
+}
+
+/// What a contact calls themselves, as opposed to the name we gave them.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    pub bio: Option<String>,
+    pub avatar: Option<Vec<u8>>,
+}
+
+pub async fn get_user_info() -> Result<Vec<UserInfo>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT
+                info.user_id,
+                info.name,
+                info.unreads,
+                profiles.name,
+                profiles.bio,
+                profiles.avatar,
+                info.last_received
+            FROM (
+                SELECT
+                    messages.user_id AS user_id,
+                    users.name AS name,
+                    SUM(CASE WHEN messages.read THEN 0 ELSE 1 END) AS unreads,
+                    MAX(COALESCE(messages.received_at, messages.time)) AS last_received
+                FROM messages
+                LEFT JOIN users
+                ON messages.user_id = users.id
+                WHERE messages.group_id IS NULL
+                GROUP BY messages.user_id, users.name
+                UNION ALL
+                SELECT
+                    users.id,
+                    users.name,
+                    count(messages.id),
+                    NULL
+                FROM users
+                LEFT JOIN messages
+                ON messages.user_id = users.id AND messages.group_id IS NULL
+                WHERE messages.user_id IS NULL
+                GROUP BY users.id, users.name
+            ) AS info
+            LEFT JOIN profiles
+            ON profiles.user_id = info.user_id",
+        )?;
+        let res = stmt
+            .query_map(params![], |row| {
+                let uid: Vec<u8> = row.get(0)?;
+                let profile_name: Option<String> = row.get(3)?;
+                Ok(UserInfo {
+                    pubkey: PublicKey::from_bytes(&uid).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            0,
+                            rusqlite::types::Type::Blob,
+                            Box::new(e),
+                        )
+                    })?,
+                    name: row.get(1)?,
+                    unreads: row.get(2)?,
+                    profile: match profile_name {
+                        Some(name) => Some(Profile {
+                            name,
+                            bio: row.get(4)?,
+                            avatar: row.get(5)?,
+                        }),
+                        None => None,
+                    },
+                    last_received: row.get(6)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+// an older profile arriving late never replaces a newer one
+pub async fn save_profile(
+    from: PublicKey,
+    nonce: [u8; 16],
+    time: i64,
+    name: String,
+    bio: Option<String>,
+    avatar: Option<[u8; 32]>,
+    horizon: i64,
+) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &from, &nonce, time, horizon)?;
+        cached_exec(
+            &conn,
+            "INSERT INTO profiles (user_id, name, bio, avatar, time) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(user_id) DO UPDATE SET name = excluded.name, bio = excluded.bio, avatar = excluded.avatar, time = excluded.time
+            WHERE excluded.time > profiles.time",
+            params![&from.as_bytes()[..], name, bio, avatar.as_ref().map(|a| &a[..]), time],
+        )?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageStatus {
+    Pending = 0,
+    Sent = 1,
+    Delivered = 2,
+    Failed = 3,
+    /// Waiting for its `send_at` time in the outbox.
+    Scheduled = 4,
+}
+
+impl rusqlite::types::ToSql for MessageStatus {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok((*self as i64).into())
+    }
+}
+
+impl rusqlite::types::FromSql for MessageStatus {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(MessageStatus::Pending),
+            1 => Ok(MessageStatus::Sent),
+            2 => Ok(MessageStatus::Delivered),
+            3 => Ok(MessageStatus::Failed),
+            4 => Ok(MessageStatus::Scheduled),
+            a => Err(rusqlite::types::FromSqlError::OutOfRange(a)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub id: i64,
+    pub tracking_id: Option<Uuid>,
+    pub time: i64,
+    pub inbound: bool,
+    pub content: String,
+    pub status: MessageStatus,
+    pub read_at: Option<i64>,
+    pub attachment: Option<i64>,
+    pub attachment_complete: Option<bool>,
+    pub uuid: Option<Uuid>,
+    pub reply_to: Option<Uuid>,
+    pub reply_to_id: Option<i64>,
+    pub edited: bool,
+    pub retracted: bool,
+    pub reactions: Vec<Reaction>,
+    pub content_type: u8,
+    /// When an inbound message reached us, by our own clock.
+    pub received_at: Option<i64>,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i64,
+    pub mine: bool,
+}
+
+fn load_reactions(conn: &Connection, messages: &mut [Message]) -> Result<(), Error> {
+    let me = PublicKey::from(&*crate::SECKEY);
+    for message in messages {
+        message.reactions = cached_query_map(
+            conn,
+            "SELECT emoji, count(*), max(reactor = ?2) FROM reactions WHERE message_id = ?1 GROUP BY emoji ORDER BY min(time) ASC",
+            params![message.id, &me.as_bytes()[..]],
+            |row| {
+                Ok(Reaction {
+                    emoji: row.get(0)?,
+                    count: row.get(1)?,
+                    mine: row.get(2)?,
+                })
+            },
+        )?;
+    }
+    Ok(())
+}
+
+fn nonce_mapper(row: &rusqlite::Row) -> Result<Option<[u8; 16]>, rusqlite::Error> {
+    nonce_mapper_at(row, 0)
+}
+
+fn nonce_mapper_at(row: &rusqlite::Row, idx: usize) -> Result<Option<[u8; 16]>, rusqlite::Error> {
+    let nonce: Option<Vec<u8>> = row.get(idx)?;
+    Ok(nonce.filter(|n| n.len() == 16).map(|n| {
+        let mut res = [0; 16];
+        res.clone_from_slice(&n);
+        res
+    }))
+}
+
+pub async fn get_messages(
+    pubkey: PublicKey,
+    limits: Limits,
+    mark_as_read: bool,
+) -> Result<(Vec<Message>, Vec<[u8; 16]>), Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        let read = if mark_as_read {
+            match (&limits.before_after, &limits.limit) {
+                (Some(BeforeAfter::Before(before)), None) => cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id < ?2 ORDER BY id DESC) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], before],
+                    nonce_mapper,
+                )?,
+                (Some(BeforeAfter::Before(before)), Some(limit)) => cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id < ?2 ORDER BY id DESC LIMIT ?3) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], before, *limit as i64],
+                    nonce_mapper,
+                )?,
+                (Some(BeforeAfter::After(after)), None) => cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id > ?2 ORDER BY id ASC) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], after],
+                    nonce_mapper,
+                )?,
+                (Some(BeforeAfter::After(after)), Some(limit)) => cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id > ?2 ORDER BY id ASC LIMIT ?3) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], after, *limit as i64],
+                    nonce_mapper,
+                )?,
+                (None, None) => cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL ORDER BY id DESC) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..]],
+                    nonce_mapper,
+                )?,
+                (None, Some(limit)) => cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL ORDER BY id DESC LIMIT ?2) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], *limit as i64],
+                    nonce_mapper,
+                )?,
+            }
+        } else {
+            Vec::new()
+        };
+        let mapper = |row: &rusqlite::Row| {
+            Ok(Message {
+                id: row.get(0)?,
+                tracking_id: row.get(1)?,
+                time: row.get(2)?,
+                inbound: row.get(3)?,
+                content: row.get(4)?,
+                status: row.get(5)?,
+                read_at: row.get(6)?,
+                attachment: row.get(7)?,
+                attachment_complete: row.get(8)?,
+                uuid: row.get(9)?,
+                reply_to: row.get(10)?,
+                reply_to_id: row.get(11)?,
+                edited: row.get(12)?,
+                retracted: row.get(13)?,
+                reactions: Vec::new(),
+                content_type: row.get(14)?,
+                received_at: row.get(15)?,
+                expires_at: row.get(16)?,
+            })
+        };
+        let mut res = match (&limits.before_after, &limits.limit) {
+            (Some(BeforeAfter::Before(before)), None) => cached_query_map(
+                &conn, 
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id < ?2 ORDER BY id DESC",
+                params![&pubkey.as_bytes()[..], before],
+                mapper,
+            )?,
+            (Some(BeforeAfter::Before(before)), Some(limit)) => cached_query_map(
+                &conn, 
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id < ?2 ORDER BY id DESC LIMIT ?3",
+                params![&pubkey.as_bytes()[..], before, *limit as i64],
+                mapper,
+            )?,
+            (Some(BeforeAfter::After(after)), None) => cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id > ?2 ORDER BY id ASC",
+                params![&pubkey.as_bytes()[..], after],
+                mapper,
+            )?,
+            (Some(BeforeAfter::After(after)), Some(limit)) => cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id > ?2 ORDER BY id ASC LIMIT ?3",
+                params![&pubkey.as_bytes()[..], after, *limit as i64],
+                mapper,
+            )?,
+            (None, None) => cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL ORDER BY id DESC",
+                params![&pubkey.as_bytes()[..]],
+                mapper,
+            )?,
+            (None, Some(limit)) => cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL ORDER BY id DESC LIMIT ?2",
+                params![&pubkey.as_bytes()[..], *limit as i64],
+                mapper,
+            )?,
+        };
+        load_reactions(&conn, &mut res)?;
+        conn.commit()?;
+        Ok::<_, Error>((res, read.into_iter().flatten().collect()))
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn get_new_messages(
+    pubkey: PublicKey,
+    limit: Option<usize>,
+    mark_as_read: bool,
+) -> Result<(Vec<Message>, Vec<[u8; 16]>), Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        let id: Option<i64> = cached_query_row(
+            &conn, 
+            "SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND read = false ORDER BY id ASC LIMIT 1",
+            params![&pubkey.as_bytes()[..]],
+            |row| row.get(0),
+        )?;
+        let id = if let Some(id) = id {
+            id
+        } else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+        let read = if mark_as_read {
+            if let Some(limit) = limit {
+                cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id >= ?2 ORDER BY id ASC LIMIT ?3) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], id, limit as i64],
+                    nonce_mapper,
+                )?
+            } else {
+                cached_query_map(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE user_id = ?1 AND group_id IS NULL AND read = false AND id IN (SELECT id FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id >= ?2 ORDER BY id ASC) RETURNING nonce",
+                    params![&pubkey.as_bytes()[..], id],
+                    nonce_mapper,
+                )?
+            }
+        } else {
+            Vec::new()
+        };
+        let mapper = |row: &rusqlite::Row| {
+            Ok(Message {
+                id: row.get(0)?,
+                tracking_id: row.get(1)?,
+                time: row.get(2)?,
+                inbound: row.get(3)?,
+                content: row.get(4)?,
+                status: row.get(5)?,
+                read_at: row.get(6)?,
+                attachment: row.get(7)?,
+                attachment_complete: row.get(8)?,
+                uuid: row.get(9)?,
+                reply_to: row.get(10)?,
+                reply_to_id: row.get(11)?,
+                edited: row.get(12)?,
+                retracted: row.get(13)?,
+                reactions: Vec::new(),
+                content_type: row.get(14)?,
+                received_at: row.get(15)?,
+                expires_at: row.get(16)?,
+            })
+        };
+        let mut res = if let Some(limit) = limit {
+            cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id >= ?2 ORDER BY id ASC LIMIT ?3",
+                params![&pubkey.as_bytes()[..], id, limit as i64],
+                mapper
+            )?
+        } else {
+            cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.user_id = messages.user_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at FROM messages WHERE user_id = ?1 AND group_id IS NULL AND id >= ?2 ORDER BY id ASC",
+                params![&pubkey.as_bytes()[..], id],
+                mapper
+            )?
+        };
+        load_reactions(&conn, &mut res)?;
+        conn.commit()?;
+        Ok::<_, Error>((res, read.into_iter().flatten().collect()))
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn get_capabilities(pubkey: PublicKey, since: i64) -> Result<Option<Capabilities>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT versions, max_message_size, features FROM capabilities WHERE user_id = ?1 AND updated_at >= ?2",
+            params![&pubkey.as_bytes()[..], since],
+            |row| {
+                let features: String = row.get(2)?;
+                Ok(Capabilities {
+                    versions: row.get(0)?,
+                    max_message_size: row.get::<_, i64>(1)? as u64,
+                    features: features.split_whitespace().map(|f| f.to_owned()).collect(),
+                })
+            },
+        )?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_capabilities(pubkey: PublicKey, capabilities: Capabilities, time: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO capabilities (user_id, versions, max_message_size, features, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(user_id) DO UPDATE SET versions = excluded.versions, max_message_size = excluded.max_message_size, features = excluded.features, updated_at = excluded.updated_at",
+            params![
+                &pubkey.as_bytes()[..],
+                capabilities.versions,
+                capabilities.max_message_size.min(i64::MAX as u64) as i64,
+                capabilities.features.join(" "),
+                time
+            ],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+// id, user_id, state
+type SessionRow = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+fn session_mapper(row: &rusqlite::Row) -> Result<SessionRow, rusqlite::Error> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn parse_session((id, user_id, state): SessionRow) -> Result<Session, Error> {
+    let mut id_buf = [0; 16];
+    id_buf.clone_from_slice(&id);
+    Session::parse(
+        id_buf,
+        PublicKey::from_bytes(&user_id)?,
+        &PublicKey::from(&*crate::SECKEY),
+        &state,
+    )
+}
+
+// the most recently used session wins when both sides started one at once
+pub async fn get_session(pubkey: PublicKey) -> Result<Option<Session>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT id, user_id, state FROM sessions WHERE user_id = ?1 ORDER BY updated_at DESC, rowid DESC LIMIT 1",
+            params![&pubkey.as_bytes()[..]],
+            session_mapper,
+        )?;
+        res.map(parse_session).transpose()
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn get_session_by_id(id: [u8; 16]) -> Result<Option<Session>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let res = cached_query_row(
+            &conn,
+            "SELECT id, user_id, state FROM sessions WHERE id = ?1",
+            params![&id[..]],
+            session_mapper,
+        )?;
+        res.map(parse_session).transpose()
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_session(session: Session, time: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "DELETE FROM sessions WHERE user_id = ?1 AND id != ?2 AND updated_at < ?3",
+            params![&session.peer.as_bytes()[..], &session.id[..], time - crate::CONFIG.replay_window],
+        )?;
+        cached_exec(
+            &conn,
+            "INSERT INTO sessions (id, user_id, state, updated_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(id) DO UPDATE SET state = excluded.state, updated_at = excluded.updated_at",
+            params![&session.id[..], &session.peer.as_bytes()[..], session.encode(), time],
+        )?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn del_sessions(pubkey: PublicKey) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_exec(
+            &conn,
+            "DELETE FROM sessions WHERE user_id = ?1",
+            params![&pubkey.as_bytes()[..]],
+        )?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+fn scalar_mapper(row: &rusqlite::Row) -> Result<Scalar, rusqlite::Error> {
+    let secret: Vec<u8> = row.get(0)?;
+    let mut bits = [0; 32];
+    if secret.len() != 32 {
+        return Err(rusqlite::Error::InvalidColumnType(0, "secret".to_owned(), rusqlite::types::Type::Blob));
+    }
+    bits.clone_from_slice(&secret);
+    Ok(Scalar::from_bits(bits))
+}
+
+pub async fn get_current_prekey(since: i64) -> Result<Option<Scalar>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_query_row(
+            &conn,
+            "SELECT secret FROM prekeys WHERE created_at >= ?1 ORDER BY created_at DESC LIMIT 1",
+            params![since],
+            scalar_mapper,
+        )
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn get_prekey(public: MontgomeryPoint) -> Result<Option<Scalar>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_query_row(
+            &conn,
+            "SELECT secret FROM prekeys WHERE public = ?1",
+            params![&public.as_bytes()[..]],
+            scalar_mapper,
+        )
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_prekey(secret: Scalar, time: i64, horizon: i64) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "DELETE FROM prekeys WHERE created_at < ?1",
+            params![horizon],
+        )?;
+        cached_exec(
+            &conn,
+            "INSERT INTO prekeys (public, secret, created_at) VALUES (?1, ?2, ?3)",
+            params![&(X25519_BASEPOINT * secret).as_bytes()[..], &secret.as_bytes()[..], time],
+        )?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub id: Uuid,
+    pub name: String,
+    /// Everyone but us.
+    pub members: Vec<PublicKey>,
+    /// False once we have left or been removed.
+    pub active: bool,
+}
+
+fn is_group_member(conn: &Connection, group: &Uuid, pubkey: &PublicKey) -> Result<bool, Error> {
+    Ok(cached_query_row(
+        conn,
+        "SELECT 1 FROM group_members JOIN groups ON groups.id = group_members.group_id WHERE group_id = ?1 AND user_id = ?2 AND groups.active",
+        params![group, &pubkey.as_bytes()[..]],
+        |_| Ok(()),
+    )?
+    .is_some())
+}
+
+fn load_group(conn: &Connection, id: Uuid) -> Result<Option<Group>, Error> {
+    let group = cached_query_row(
+        conn,
+        "SELECT name, active FROM groups WHERE id = ?1",
+        params![id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let (name, active) = match group {
+        Some(group) => group,
+        None => return Ok(None),
+    };
+    let members = cached_query_map(
+        conn,
+        "SELECT user_id FROM group_members WHERE group_id = ?1 ORDER BY rowid",
+        params![id],
+        |row| row.get::<_, Vec<u8>>(0),
+    )?
+    .into_iter()
+    .map(|member| PublicKey::from_bytes(&member))
+    .collect::<Result<_, _>>()?;
+    Ok(Some(Group {
+        id,
+        name,
+        members,
+        active,
+    }))
+}
+
+pub async fn get_group(id: Uuid) -> Result<Option<Group>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        load_group(&conn, id)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_group(id: Uuid, name: String, members: Vec<PublicKey>, active: bool) -> Result<(), Error> {
+    let time = crate::util::now();
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO groups (id, name, active, created_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(id) DO UPDATE SET name = excluded.name, active = excluded.active",
+            params![id, name, active, time],
+        )?;
+        cached_exec(
+            &conn,
+            "DELETE FROM group_members WHERE group_id = ?1",
+            params![id],
+        )?;
+        for member in members {
+            cached_exec(
+                &conn,
+                "INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?1, ?2)",
+                params![id, &member.as_bytes()[..]],
+            )?;
+        }
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+// applies a management message from another member
+pub async fn save_group_update(message: NewInboundMessage, horizon: i64) -> Result<(), Error> {
+    let (group, op, name, members) = match (&message.group, &message.content) {
+        (Some(group), Content::Group { op, name, members }) => (*group, *op, name.clone(), members.clone()),
+        _ => failure::bail!("not a group update"),
+    };
+    if name.len() > crate::group::MAX_NAME_LEN {
+        failure::bail!("Group name too long");
+    }
+    let local = PublicKey::from(&*crate::SECKEY);
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        check_nonce(&conn, &message.from, &message.nonce, message.time, horizon)?;
+        let active = cached_query_row(
+            &conn,
+            "SELECT active FROM groups WHERE id = ?1",
+            params![group],
+            |row| row.get::<_, bool>(0),
+        )?;
+        let known = active.is_some();
+        let is_member = cached_query_row(
+            &conn,
+            "SELECT 1 FROM group_members WHERE group_id = ?1 AND user_id = ?2",
+            params![group, &message.from.as_bytes()[..]],
+            |_| Ok(()),
+        )?
+        .is_some();
+        match op {
+            GroupOp::Create | GroupOp::Add => {
+                if known && !is_member {
+                    failure::bail!("Not a group member");
+                }
+                // once we have left, only a member adding us back brings the group back
+                if active == Some(false) && !(op == GroupOp::Add && members.contains(&local)) {
+                    failure::bail!("Not a group member");
+                }
+                if !known && !members.contains(&local) {
+                    failure::bail!("Unknown group");
+                }
+                cached_exec(
+                    &conn,
+                    "INSERT INTO groups (id, name, active, created_at) VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT(id) DO UPDATE SET name = excluded.name, active = active OR excluded.active",
+                    params![group, name, members.contains(&local), message.time],
+                )?;
+                for member in members.iter().chain(std::iter::once(&message.from)) {
+                    if member != &local {
+                        cached_exec(
+                            &conn,
+                            "INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?1, ?2)",
+                            params![group, &member.as_bytes()[..]],
+                        )?;
+                    }
+                }
+                let count = cached_query_row(
+                    &conn,
+                    "SELECT count(*) FROM group_members WHERE group_id = ?1",
+                    params![group],
+                    |row| row.get::<_, i64>(0),
+                )?
+                .unwrap_or(0);
+                if count as usize > crate::group::MAX_MEMBERS {
+                    failure::bail!("Too many group members");
+                }
+            }
+            GroupOp::Remove | GroupOp::Leave => {
+                if !is_member {
+                    failure::bail!("Not a group member");
+                }
+                let removed = if op == GroupOp::Leave {
+                    vec![message.from]
+                } else {
+                    members
+                };
+                for member in removed {
+                    if member == local {
+                        cached_exec(
+                            &conn,
+                            "UPDATE groups SET active = false WHERE id = ?1",
+                            params![group],
+                        )?;
+                    } else {
+                        cached_exec(
+                            &conn,
+                            "DELETE FROM group_members WHERE group_id = ?1 AND user_id = ?2",
+                            params![group, &member.as_bytes()[..]],
+                        )?;
+                    }
+                }
+            }
+        }
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupInfo {
+    pub group: Group,
+    pub unreads: i64,
+}
+
+pub async fn get_groups() -> Result<Vec<GroupInfo>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        let groups = cached_query_map(
+            &conn,
+            "SELECT id, (SELECT count(*) FROM messages WHERE messages.group_id = groups.id AND NOT messages.read) FROM groups ORDER BY created_at",
+            params![],
+            |row| Ok((row.get::<_, Uuid>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+        let mut res = Vec::with_capacity(groups.len());
+        for (id, unreads) in groups {
+            if let Some(group) = load_group(&conn, id)? {
+                res.push(GroupInfo { group, unreads });
+            }
+        }
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn save_out_group_message(message: NewOutboundMessage, members: Vec<PublicKey>) -> Result<i64, Error> {
+    let content = match &message.content {
+        Content::Text(text) => text.clone(),
+        _ => failure::bail!("not a text message"),
+    };
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "INSERT INTO messages (tracking_id, user_id, inbound, time, content, read, status, nonce, uuid, reply_to, content_type, group_id) VALUES (?1, ?2, false, ?3, ?4, true, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![message.tracking_id, &message.to.as_bytes()[..], message.time, content, MessageStatus::Pending, &message.nonce[..], message.uuid, message.reply_to, message.content.kind(), message.group],
+        )?;
+        let id = conn.last_insert_rowid();
+        for member in members {
+            cached_exec(
+                &conn,
+                "INSERT INTO deliveries (message_id, user_id, status, attempts) VALUES (?1, ?2, ?3, 1)",
+                params![id, &member.as_bytes()[..], MessageStatus::Pending],
+            )?;
+        }
+        conn.commit()?;
+        Ok::<_, Error>(id)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn set_delivery(id: i64, pubkey: PublicKey, status: MessageStatus) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "UPDATE deliveries SET status = ?3, next_attempt_at = NULL WHERE message_id = ?1 AND user_id = ?2",
+            params![id, &pubkey.as_bytes()[..], status],
+        )?;
+        update_group_status(&conn, id)?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Records a failed attempt to reach one member, giving up on them if there is no `retry_at`.
+pub async fn save_failed_delivery(id: i64, pubkey: PublicKey, error: String, retry_at: Option<i64>) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        cached_exec(
+            &conn,
+            "UPDATE deliveries SET last_error = ?3, next_attempt_at = ?4, status = CASE WHEN ?4 IS NULL THEN ?5 ELSE status END WHERE message_id = ?1 AND user_id = ?2",
+            params![id, &pubkey.as_bytes()[..], error, retry_at, MessageStatus::Failed],
+        )?;
+        update_group_status(&conn, id)?;
+        conn.commit()?;
+        Ok::<_, Error>(())
+    })
+    .await??;
+    Ok(())
+}
+
+// a group message is failed or delivered once every member is; it stays pending while members
+// are still being retried and nobody has received it yet
+fn update_group_status(conn: &rusqlite::Transaction, id: i64) -> Result<(), Error> {
+    let statuses = cached_query_map(
+        conn,
+        "SELECT status FROM deliveries WHERE message_id = ?1",
+        params![id],
+        |row| row.get::<_, MessageStatus>(0),
+    )?;
+    let status = if statuses.iter().all(|s| *s == MessageStatus::Failed) {
+        MessageStatus::Failed
+    } else if statuses.iter().all(|s| *s == MessageStatus::Delivered) {
+        MessageStatus::Delivered
+    } else if statuses
+        .iter()
+        .any(|s| *s == MessageStatus::Sent || *s == MessageStatus::Delivered)
+    {
+        MessageStatus::Sent
+    } else {
+        MessageStatus::Pending
+    };
+    cached_exec(
+        conn,
+        "UPDATE messages SET status = ?2 WHERE id = ?1",
+        params![id, status],
+    )?;
+    Ok(())
+}
+
+// like `claim_due_messages`, but for group members still to be reached; each comes back addressed
+// to the member, under the id of the group message
+pub async fn claim_due_deliveries(now: i64, limit: usize) -> Result<Vec<Queued>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        let due = cached_query_map(
+            &conn,
+            "SELECT m.id, m.tracking_id, d.user_id, m.time, m.content, m.nonce, m.uuid, m.reply_to, m.group_id, d.attempts FROM deliveries d JOIN messages m ON m.id = d.message_id WHERE d.next_attempt_at <= ?1 AND d.status = ?2 ORDER BY d.next_attempt_at LIMIT ?3",
+            params![now, MessageStatus::Pending, limit as i64],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<Uuid>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                    nonce_mapper_at(row, 5)?,
+                    row.get::<_, Option<Uuid>>(6)?,
+                    row.get::<_, Option<Uuid>>(7)?,
+                    row.get::<_, Option<Uuid>>(8)?,
+                    row.get::<_, u32>(9)?,
+                ))
+            },
+        )?;
+        let mut res = Vec::with_capacity(due.len());
+        for (id, tracking_id, to, time, content, nonce, uuid, reply_to, group, attempts) in due {
+            cached_exec(
+                &conn,
+                "UPDATE deliveries SET next_attempt_at = NULL, attempts = attempts + 1 WHERE message_id = ?1 AND user_id = ?2",
+                params![id, &to[..]],
+            )?;
+            res.push(Queued {
+                id,
+                message: NewOutboundMessage {
+                    tracking_id,
+                    to: PublicKey::from_bytes(&to)?,
+                    nonce: nonce.ok_or_else(|| failure::format_err!("Message {} has no nonce", id))?,
+                    uuid: uuid.unwrap_or_else(Uuid::new_v4),
+                    reply_to,
+                    group,
+                    time,
+                    ttl: None,
+                    content: Content::Text(content),
+                },
+                attempts: attempts + 1,
+                queued_at: time,
+            });
+        }
+        conn.commit()?;
+        Ok::<_, Error>(res)
+    })
+    .await??;
+    Ok(res)
+}
+
+pub async fn get_deliveries(id: i64) -> Result<Vec<(PublicKey, MessageStatus)>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = POOL.get()?;
+        cached_query_map(
+            &conn,
+            "SELECT user_id, status FROM deliveries WHERE message_id = ?1 ORDER BY rowid",
+            params![id],
+            |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, MessageStatus>(1)?)),
+        )?
+        .into_iter()
+        .map(|(pubkey, status)| Ok((PublicKey::from_bytes(&pubkey)?, status)))
+        .collect::<Result<Vec<_>, Error>>()
+    })
+    .await??;
+    Ok(res)
+}
+
+// group conversations don't send read receipts, so each message comes back with its sender instead
+pub async fn get_group_messages(id: Uuid, limits: Limits, mark_as_read: bool) -> Result<Vec<(Vec<u8>, Message)>, Error> {
+    let res = tokio::task::spawn_blocking(move || {
+        let mut gconn = POOL.get()?;
+        let conn = gconn.transaction()?;
+        let mapper = |row: &rusqlite::Row| {
+            Ok((row.get(17)?, Message {
+                id: row.get(0)?,
+                tracking_id: row.get(1)?,
+                time: row.get(2)?,
+                inbound: row.get(3)?,
+                content: row.get(4)?,
+                status: row.get(5)?,
+                read_at: row.get(6)?,
+                attachment: row.get(7)?,
+                attachment_complete: row.get(8)?,
+                uuid: row.get(9)?,
+                reply_to: row.get(10)?,
+                reply_to_id: row.get(11)?,
+                edited: row.get(12)?,
+                retracted: row.get(13)?,
+                reactions: Vec::new(),
+                content_type: row.get(14)?,
+                received_at: row.get(15)?,
+                expires_at: row.get(16)?,
+            }))
+        };
+        let limit = limits.limit.map(|limit| limit as i64).unwrap_or(-1);
+        let res = match limits.before_after {
+            Some(BeforeAfter::After(after)) => cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.group_id = messages.group_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at, user_id FROM messages WHERE group_id = ?1 AND id > ?2 ORDER BY id ASC LIMIT ?3",
+                params![id, after, limit],
+                mapper,
+            )?,
+            before => cached_query_map(
+                &conn,
+                "SELECT id, tracking_id, time, inbound, content, status, read_at, (SELECT attachments.id FROM attachments WHERE attachments.message_id = messages.id), (SELECT attachments.complete FROM attachments WHERE attachments.message_id = messages.id), uuid, reply_to, (SELECT quoted.id FROM messages AS quoted WHERE quoted.group_id = messages.group_id AND quoted.uuid = messages.reply_to), edited, retracted, content_type, received_at, expires_at, user_id FROM messages WHERE group_id = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+                params![
+                    id,
+                    match before {
+                        Some(BeforeAfter::Before(before)) => before,
+                        _ => i64::MAX,
+                    },
+                    limit
+                ],
+                mapper,
+            )?,
+        };
+        if mark_as_read {
+            for (_, msg) in &res {
+                cached_exec(
+                    &conn,
+                    "UPDATE messages SET read = true WHERE id = ?1 AND read = false",
+                    params![msg.id],
+                )?;
+            }
+        }
+        let (senders, mut messages): (Vec<_>, Vec<_>) = res.into_iter().unzip();
+        load_reactions(&conn, &mut messages)?;
+        conn.commit()?;
+        Ok::<_, Error>(senders.into_iter().zip(messages).collect())
+    })
+    .await??;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pubkey() -> PublicKey {
+        PublicKey::from(&ed25519_dalek::SecretKey::generate(&mut rand::rngs::OsRng))
+    }
+
+    fn text(from: PublicKey, nonce: [u8; 16], time: i64) -> NewInboundMessage {
+        NewInboundMessage {
+            from,
+            nonce,
+            uuid: Uuid::new_v4(),
+            reply_to: None,
+            group: None,
+            time,
+            ttl: None,
+            content: Content::Text("hello".to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_nonce() {
+        migrated().await;
+        let (alice, bob) = (pubkey(), pubkey());
+        let nonce = rand::random();
+        save_in_message(text(alice, nonce, 1000), 0).await.unwrap();
+        let replay = save_in_message(text(alice, nonce, 1000), 0).await;
+        assert_eq!(replay.unwrap_err().to_string(), "Replayed message");
+        // nonces are per sender
+        save_in_message(text(bob, nonce, 1000), 0).await.unwrap();
+        save_in_message(text(alice, rand::random(), 1000), 0).await.unwrap();
+        // and forgotten once they fall behind the horizon, when the clock check takes over
+        save_in_message(text(alice, nonce, 3000), 2000).await.unwrap();
+    }
+
+    fn attachment(from: PublicKey, size: u64) -> NewInboundMessage {
+        NewInboundMessage {
+            content: Content::Attachment { name: "file".to_owned(), size, hash: [0; 32] },
+            ..text(from, rand::random(), 1000)
+        }
+    }
+
+    #[tokio::test]
+    async fn caps_incomplete_attachments() {
+        migrated().await;
+        let (alice, bob) = (pubkey(), pubkey());
+        for _ in 0..crate::attachment::MAX_INCOMPLETE {
+            save_in_attachment(attachment(alice, 1024), 0).await.unwrap();
+        }
+        let res = save_in_attachment(attachment(alice, 1024), 0).await;
+        assert_eq!(res.unwrap_err().to_string(), "Too many incomplete attachments");
+        // empty attachments are complete on arrival, and the cap is per peer
+        save_in_attachment(attachment(alice, 0), 0).await.unwrap();
+        save_in_attachment(attachment(bob, 1024), 0).await.unwrap();
+    }
+
+    fn group_update(from: PublicKey, group: Uuid, op: GroupOp, members: Vec<PublicKey>) -> NewInboundMessage {
+        NewInboundMessage {
+            group: Some(group),
+            content: Content::Group { op, name: "group".to_owned(), members },
+            ..text(from, rand::random(), 1000)
+        }
+    }
+
+    #[tokio::test]
+    async fn left_groups_stay_left() {
+        migrated().await;
+        let (local, alice, bob) = (PublicKey::from(&*crate::SECKEY), pubkey(), pubkey());
+        let group = Uuid::new_v4();
+        save_group_update(group_update(alice, group, GroupOp::Create, vec![local, bob]), 0).await.unwrap();
+        save_group_update(group_update(alice, group, GroupOp::Remove, vec![local]), 0).await.unwrap();
+        assert!(!get_group(group).await.unwrap().unwrap().active);
+        // a former co-member cannot pull us back in by recreating the group or adding someone else
+        for (op, members) in [(GroupOp::Create, vec![local, bob]), (GroupOp::Add, vec![bob])] {
+            let res = save_group_update(group_update(alice, group, op, members), 0).await;
+            assert_eq!(res.unwrap_err().to_string(), "Not a group member");
+            assert!(!get_group(group).await.unwrap().unwrap().active);
+        }
+        save_group_update(group_update(bob, group, GroupOp::Add, vec![local]), 0).await.unwrap();
+        assert!(get_group(group).await.unwrap().unwrap().active);
+    }
+
+    #[tokio::test]
+    async fn del_user_forgets_everything() {
+        migrated().await;
+        let alice = pubkey();
+        save_in_message(text(alice, rand::random(), 1000), 0).await.unwrap();
+        save_capabilities(alice, crate::capabilities::Capabilities::legacy(), 1000).await.unwrap();
+        del_user(alice).await.unwrap();
+        let conn = POOL.get().unwrap();
+        for table in ["messages", "capabilities", "nonces", "deliveries"] {
+            let count: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM {} WHERE user_id = ?1", table), params![&alice.as_bytes()[..]], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 0, "{}", table);
+        }
+    }
 }
@@ -1,21 +1,26 @@
-use ed25519_dalek::PublicKey;
 use failure::Error;
 
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "camelCase")]
 pub enum Query {
-    User { pubkey: String },
+    User {
+        pubkey: String,
+    },
+    Group {
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        id: uuid::Uuid,
+    },
+    Scheduled {
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        id: i64,
+    },
 }
 
 pub async fn handle(q: Query) -> Result<(), Error> {
     match q {
-        Query::User { pubkey } => {
-            crate::db::del_user(PublicKey::from_bytes(
-                &base32::decode(base32::Alphabet::RFC4648 { padding: false }, &pubkey)
-                    .ok_or_else(|| failure::format_err!("invalid pubkey"))?,
-            )?)
-            .await
-        }
+        Query::User { pubkey } => crate::db::del_user(crate::onion::parse_pubkey(&pubkey)?).await,
+        Query::Group { id } => crate::group::leave(id).await,
+        Query::Scheduled { id } => crate::db::del_scheduled(id).await,
     }
 }
@@ -0,0 +1,503 @@
+use std::collections::BTreeMap;
+
+use ed25519_dalek::PublicKey;
+use failure::Error;
+use uuid::Uuid;
+
+use crate::group::GroupOp;
+use crate::message::{Content, Inbound, NewInboundMessage, NewOutboundMessage};
+use crate::presence::Signal;
+
+pub const VERSION: u8 = 0;
+
+// header fields
+const TO: u8 = 0;
+const NONCE: u8 = 1;
+const TIME: u8 = 2;
+const UUID: u8 = 3;
+const REPLY_TO: u8 = 4;
+const KIND: u8 = 5;
+const FALLBACK: u8 = 6;
+const GROUP: u8 = 7;
+const TTL: u8 = 8;
+// content fields
+const TEXT: u8 = 16;
+const NONCES: u8 = 17;
+const SIGNAL: u8 = 18;
+const NAME: u8 = 19;
+const SIZE: u8 = 20;
+const HASH: u8 = 21;
+const ATTACHMENT: u8 = 22;
+const OFFSET: u8 = 23;
+const DATA: u8 = 24;
+const TARGET: u8 = 25;
+const EMOJI: u8 = 26;
+const OP: u8 = 27;
+const MEMBERS: u8 = 28;
+const BIO: u8 = 29;
+const AVATAR: u8 = 30;
+
+fn eof() -> std::io::Error {
+    std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
+}
+
+/// The signed payload of wire versions 1 and 2: `envelope version || (tag || len u32 || value)*`.
+///
+/// Header fields are `to`, `nonce`, `time`, `uuid`, the optional `reply to`, `group` and `ttl`
+/// (u32 seconds after `time` at which both ends delete the message) and the `kind`, after which
+/// each kind has its own fields:
+///
+/// - 0 text: `text`
+/// - 1 read receipt: `nonces`, concatenated
+/// - 2 presence: `signal` (0 typing, 1 online)
+/// - 3 attachment: `name`, `size`, `hash` (sha3-256)
+/// - 4 attachment chunk: `attachment` nonce, `offset`, `data`
+/// - 5 edit: `target` uuid, `text`
+/// - 6 retraction: `target` uuid
+/// - 7 reaction: `target` uuid, `emoji`, an empty emoji removing the reaction
+/// - 8 group management: `op` (0 create, 1 add, 2 remove, 3 leave), `name`, `members`,
+///   concatenated pubkeys
+/// - 9 profile: `name` and the optional `bio` and `avatar` hash (sha3-256)
+///
+/// Fields may come in any order and unknown fields are skipped, so new fields can be added
+/// without a new envelope version. A kind we do not know is surfaced as [`Inbound::Unsupported`],
+/// along with the optional `fallback` text its sender offered for older peers.
+pub fn encode(message: &NewOutboundMessage) -> Vec<u8> {
+    let mut res = vec![VERSION];
+    push(&mut res, TO, message.to.as_bytes());
+    push(&mut res, NONCE, &message.nonce);
+    push(&mut res, TIME, &i64::to_be_bytes(message.time));
+    push(&mut res, UUID, message.uuid.as_bytes());
+    if let Some(reply_to) = &message.reply_to {
+        push(&mut res, REPLY_TO, reply_to.as_bytes());
+    }
+    if let Some(group) = &message.group {
+        push(&mut res, GROUP, group.as_bytes());
+    }
+    if let Some(ttl) = message.ttl {
+        push(&mut res, TTL, &u32::to_be_bytes(ttl));
+    }
+    push(&mut res, KIND, &[message.content.kind()]);
+    match &message.content {
+        Content::Text(text) => push(&mut res, TEXT, text.as_bytes()),
+        Content::ReadReceipt(nonces) => push(&mut res, NONCES, &nonces.concat()),
+        Content::Presence(signal) => push(&mut res, SIGNAL, &[*signal as u8]),
+        Content::Attachment { name, size, hash } => {
+            push(&mut res, NAME, name.as_bytes());
+            push(&mut res, SIZE, &u64::to_be_bytes(*size));
+            push(&mut res, HASH, hash);
+        }
+        Content::Chunk {
+            attachment,
+            offset,
+            data,
+        } => {
+            push(&mut res, ATTACHMENT, attachment);
+            push(&mut res, OFFSET, &u64::to_be_bytes(*offset));
+            push(&mut res, DATA, data);
+        }
+        Content::Edit { target, text } => {
+            push(&mut res, TARGET, target.as_bytes());
+            push(&mut res, TEXT, text.as_bytes());
+        }
+        Content::Retract { target } => push(&mut res, TARGET, target.as_bytes()),
+        Content::Reaction { target, emoji } => {
+            push(&mut res, TARGET, target.as_bytes());
+            push(&mut res, EMOJI, emoji.as_bytes());
+        }
+        Content::Group { op, name, members } => {
+            push(&mut res, OP, &[*op as u8]);
+            push(&mut res, NAME, name.as_bytes());
+            push(
+                &mut res,
+                MEMBERS,
+                &members
+                    .iter()
+                    .flat_map(|member| member.as_bytes().iter().copied())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        Content::Profile { name, bio, avatar } => {
+            push(&mut res, NAME, name.as_bytes());
+            if let Some(bio) = bio {
+                push(&mut res, BIO, bio.as_bytes());
+            }
+            if let Some(avatar) = avatar {
+                push(&mut res, AVATAR, avatar);
+            }
+        }
+    }
+    res
+}
+
+fn push(res: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    res.push(tag);
+    res.extend_from_slice(&u32::to_be_bytes(value.len() as u32));
+    res.extend_from_slice(value);
+}
+
+struct Fields<'a>(BTreeMap<u8, &'a [u8]>);
+
+impl<'a> Fields<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        match bytes.first().ok_or_else(eof)? {
+            &VERSION => (),
+            _ => failure::bail!("Unsupported envelope version"),
+        }
+        let mut fields = BTreeMap::new();
+        let mut bytes = &bytes[1..];
+        while !bytes.is_empty() {
+            let mut len = [0; 4];
+            len.clone_from_slice(bytes.get(1..5).ok_or_else(eof)?);
+            let end = 5 + u32::from_be_bytes(len) as usize;
+            if fields
+                .insert(bytes[0], bytes.get(5..end).ok_or_else(eof)?)
+                .is_some()
+            {
+                failure::bail!("Duplicate envelope field");
+            }
+            bytes = &bytes[end..];
+        }
+        Ok(Fields(fields))
+    }
+
+    fn get(&self, tag: u8) -> Option<&'a [u8]> {
+        self.0.get(&tag).copied()
+    }
+
+    fn required(&self, tag: u8) -> Result<&'a [u8], Error> {
+        self.get(tag)
+            .ok_or_else(|| failure::format_err!("Missing envelope field {}", tag))
+    }
+
+    fn fixed(&self, tag: u8, len: usize) -> Result<&'a [u8], Error> {
+        let res = self.required(tag)?;
+        if res.len() != len {
+            failure::bail!("Invalid envelope field {}", tag);
+        }
+        Ok(res)
+    }
+
+    fn string(&self, tag: u8) -> Result<String, Error> {
+        Ok(String::from_utf8(self.required(tag)?.to_vec())?)
+    }
+
+    fn uuid(&self, tag: u8) -> Result<Uuid, Error> {
+        Ok(Uuid::from_slice(self.fixed(tag, 16)?)?)
+    }
+
+    fn u32(&self, tag: u8) -> Result<u32, Error> {
+        let mut res = [0; 4];
+        res.clone_from_slice(self.fixed(tag, 4)?);
+        Ok(u32::from_be_bytes(res))
+    }
+
+    fn u64(&self, tag: u8) -> Result<u64, Error> {
+        let mut res = [0; 8];
+        res.clone_from_slice(self.fixed(tag, 8)?);
+        Ok(u64::from_be_bytes(res))
+    }
+
+    fn array_16(&self, tag: u8) -> Result<[u8; 16], Error> {
+        let mut res = [0; 16];
+        res.clone_from_slice(self.fixed(tag, 16)?);
+        Ok(res)
+    }
+}
+
+pub fn parse(from: PublicKey, to: &PublicKey, bytes: &[u8]) -> Result<Inbound, Error> {
+    let fields = Fields::parse(bytes)?;
+    if fields.fixed(TO, 32)? != to.as_bytes() {
+        failure::bail!("Message addressed to another recipient");
+    }
+    let nonce = fields.array_16(NONCE)?;
+    let uuid = fields.uuid(UUID)?;
+    let reply_to = match fields.get(REPLY_TO) {
+        Some(_) => Some(fields.uuid(REPLY_TO)?),
+        None => None,
+    };
+    let group = match fields.get(GROUP) {
+        Some(_) => Some(fields.uuid(GROUP)?),
+        None => None,
+    };
+    let ttl = match fields.get(TTL) {
+        Some(_) => Some(fields.u32(TTL)?),
+        None => None,
+    };
+    let time = fields.u64(TIME)? as i64;
+    let kind = fields.fixed(KIND, 1)?[0];
+    let content = match kind {
+        0 => Content::Text(fields.string(TEXT)?),
+        1 => {
+            let nonces = fields.required(NONCES)?;
+            if nonces.len() % 16 != 0 {
+                return Err(eof().into());
+            }
+            Content::ReadReceipt(
+                nonces
+                    .chunks(16)
+                    .map(|chunk| {
+                        let mut nonce = [0; 16];
+                        nonce.clone_from_slice(chunk);
+                        nonce
+                    })
+                    .collect(),
+            )
+        }
+        2 => Content::Presence(
+            Signal::from_u8(fields.fixed(SIGNAL, 1)?[0])
+                .ok_or_else(|| failure::format_err!("Unsupported presence signal"))?,
+        ),
+        3 => {
+            let mut hash = [0; 32];
+            hash.clone_from_slice(fields.fixed(HASH, 32)?);
+            Content::Attachment {
+                name: fields.string(NAME)?,
+                size: fields.u64(SIZE)?,
+                hash,
+            }
+        }
+        4 => Content::Chunk {
+            attachment: fields.array_16(ATTACHMENT)?,
+            offset: fields.u64(OFFSET)?,
+            data: fields.required(DATA)?.to_vec(),
+        },
+        5 => Content::Edit {
+            target: fields.uuid(TARGET)?,
+            text: fields.string(TEXT)?,
+        },
+        6 => Content::Retract {
+            target: fields.uuid(TARGET)?,
+        },
+        7 => Content::Reaction {
+            target: fields.uuid(TARGET)?,
+            emoji: fields.string(EMOJI)?,
+        },
+        8 => {
+            let members = fields.required(MEMBERS)?;
+            if members.len() % 32 != 0 {
+                return Err(eof().into());
+            }
+            Content::Group {
+                op: GroupOp::from_u8(fields.fixed(OP, 1)?[0])
+                    .ok_or_else(|| failure::format_err!("Unsupported group operation"))?,
+                name: fields.string(NAME)?,
+                members: members
+                    .chunks(32)
+                    .map(PublicKey::from_bytes)
+                    .collect::<Result<_, _>>()?,
+            }
+        }
+        9 => Content::Profile {
+            name: fields.string(NAME)?,
+            bio: match fields.get(BIO) {
+                Some(_) => Some(fields.string(BIO)?),
+                None => None,
+            },
+            avatar: match fields.get(AVATAR) {
+                Some(avatar) if avatar.len() == 32 => {
+                    let mut hash = [0; 32];
+                    hash.clone_from_slice(avatar);
+                    Some(hash)
+                }
+                Some(_) => failure::bail!("Invalid envelope field {}", AVATAR),
+                None => None,
+            },
+        },
+        kind => {
+            return Ok(Inbound::Unsupported {
+                from,
+                nonce,
+                uuid,
+                time,
+                kind,
+                fallback: match fields.get(FALLBACK) {
+                    Some(_) => Some(fields.string(FALLBACK)?),
+                    None => None,
+                },
+            })
+        }
+    };
+    Ok(Inbound::Message(NewInboundMessage {
+        from,
+        nonce,
+        uuid,
+        reply_to,
+        group,
+        time,
+        ttl,
+        content,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pubkey() -> PublicKey {
+        PublicKey::from(&ed25519_dalek::SecretKey::generate(&mut rand::rngs::OsRng))
+    }
+
+    fn message(to: PublicKey, content: Content) -> NewOutboundMessage {
+        NewOutboundMessage {
+            tracking_id: None,
+            to,
+            nonce: rand::random(),
+            uuid: Uuid::new_v4(),
+            reply_to: None,
+            group: None,
+            time: 1_600_000_000,
+            ttl: None,
+            content,
+        }
+    }
+
+    fn kinds() -> Vec<Content> {
+        vec![
+            Content::Text("hello".to_owned()),
+            Content::ReadReceipt(vec![[1; 16], [2; 16]]),
+            Content::Presence(Signal::Online),
+            Content::Attachment {
+                name: "cat.png".to_owned(),
+                size: 1 << 20,
+                hash: [3; 32],
+            },
+            Content::Chunk {
+                attachment: [4; 16],
+                offset: 4096,
+                data: vec![5; 100],
+            },
+            Content::Edit {
+                target: Uuid::new_v4(),
+                text: "hello again".to_owned(),
+            },
+            Content::Retract {
+                target: Uuid::new_v4(),
+            },
+            Content::Reaction {
+                target: Uuid::new_v4(),
+                emoji: "👍".to_owned(),
+            },
+            Content::Group {
+                op: GroupOp::Add,
+                name: "friends".to_owned(),
+                members: vec![pubkey(), pubkey()],
+            },
+            Content::Profile {
+                name: "alice".to_owned(),
+                bio: Some("hi".to_owned()),
+                avatar: Some([6; 32]),
+            },
+        ]
+    }
+
+    // the envelope is canonical, so what was received encodes back to what was sent
+    fn assert_parsed(inbound: Inbound, from: &PublicKey, sent: &NewOutboundMessage) {
+        let msg = match inbound {
+            Inbound::Message(msg) => msg,
+            Inbound::Unsupported { .. } => panic!("parsed as unsupported"),
+        };
+        assert_eq!(&msg.from, from);
+        let received = NewOutboundMessage {
+            tracking_id: None,
+            to: sent.to,
+            nonce: msg.nonce,
+            uuid: msg.uuid,
+            reply_to: msg.reply_to,
+            group: msg.group,
+            time: msg.time,
+            ttl: msg.ttl,
+            content: msg.content,
+        };
+        assert_eq!(encode(&received), encode(sent));
+    }
+
+    #[test]
+    fn round_trip() {
+        let (from, to) = (pubkey(), pubkey());
+        for (kind, content) in kinds().into_iter().enumerate() {
+            assert_eq!(content.kind() as usize, kind);
+            let mut msg = message(to, content);
+            assert_parsed(parse(from, &to, &encode(&msg)).unwrap(), &from, &msg);
+            msg.reply_to = Some(Uuid::new_v4());
+            msg.group = Some(Uuid::new_v4());
+            msg.ttl = Some(60);
+            assert_parsed(parse(from, &to, &encode(&msg)).unwrap(), &from, &msg);
+        }
+        let msg = message(to, Content::Text("not for you".to_owned()));
+        assert!(parse(from, &pubkey(), &encode(&msg)).is_err());
+    }
+
+    #[test]
+    fn skips_unknown_fields() {
+        let (from, to) = (pubkey(), pubkey());
+        let msg = message(to, Content::Text("hello".to_owned()));
+        let encoded = encode(&msg);
+        let mut bytes = vec![VERSION];
+        push(&mut bytes, 200, b"from a newer peer");
+        bytes.extend_from_slice(&encoded[1..]);
+        push(&mut bytes, 201, &[]);
+        assert_parsed(parse(from, &to, &bytes).unwrap(), &from, &msg);
+    }
+
+    #[test]
+    fn unknown_kind_is_unsupported() {
+        let (from, to) = (pubkey(), pubkey());
+        let uuid = Uuid::new_v4();
+        for fallback in &[Some("upgrade to see this"), None] {
+            let mut bytes = vec![VERSION];
+            push(&mut bytes, TO, to.as_bytes());
+            push(&mut bytes, NONCE, &[7; 16]);
+            push(&mut bytes, TIME, &i64::to_be_bytes(1_600_000_000));
+            push(&mut bytes, UUID, uuid.as_bytes());
+            push(&mut bytes, KIND, &[42]);
+            push(&mut bytes, 200, b"a field only kind 42 knows");
+            if let Some(fallback) = fallback {
+                push(&mut bytes, FALLBACK, fallback.as_bytes());
+            }
+            match parse(from, &to, &bytes).unwrap() {
+                Inbound::Unsupported {
+                    from: sender,
+                    nonce,
+                    uuid: parsed_uuid,
+                    time,
+                    kind,
+                    fallback: parsed_fallback,
+                } => {
+                    assert_eq!(sender, from);
+                    assert_eq!(nonce, [7; 16]);
+                    assert_eq!(parsed_uuid, uuid);
+                    assert_eq!(time, 1_600_000_000);
+                    assert_eq!(kind, 42);
+                    assert_eq!(parsed_fallback.as_deref(), *fallback);
+                }
+                Inbound::Message(_) => panic!("parsed an unknown kind"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let (from, to) = (pubkey(), pubkey());
+        for content in kinds() {
+            let bytes = encode(&message(to, content));
+            let mut boundaries = vec![1];
+            while boundaries[boundaries.len() - 1] < bytes.len() {
+                let at = boundaries[boundaries.len() - 1];
+                let mut len = [0; 4];
+                len.clone_from_slice(&bytes[at + 1..at + 5]);
+                boundaries.push(at + 5 + u32::from_be_bytes(len) as usize);
+            }
+            // cut between fields, only optional ones may go missing
+            for len in 0..bytes.len() {
+                let res = parse(from, &to, &bytes[..len]);
+                if !boundaries.contains(&len) {
+                    assert!(res.is_err());
+                }
+            }
+        }
+        let mut bytes = encode(&message(to, Content::Text("hello".to_owned())));
+        bytes.extend_from_slice(&[200, 0xff, 0xff, 0xff, 0xff, 0]);
+        assert!(parse(from, &to, &bytes).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use ed25519_dalek::PublicKey;
+use failure::Error;
+use uuid::Uuid;
+
+use crate::db::{MessageStatus, Queued};
+use crate::message::{Content, NewInboundMessage, NewOutboundMessage};
+
+// both are written with a one byte length in the Users listing
+pub const MAX_NAME_LEN: usize = 255;
+pub const MAX_MEMBERS: usize = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupOp {
+    Create = 0,
+    Add = 1,
+    Remove = 2,
+    Leave = 3,
+}
+
+impl GroupOp {
+    pub fn from_u8(op: u8) -> Option<Self> {
+        match op {
+            0 => Some(GroupOp::Create),
+            1 => Some(GroupOp::Add),
+            2 => Some(GroupOp::Remove),
+            3 => Some(GroupOp::Leave),
+            _ => None,
+        }
+    }
+}
+
+fn local() -> PublicKey {
+    PublicKey::from(&*crate::SECKEY)
+}
+
+async fn get_active(id: Uuid) -> Result<crate::db::Group, Error> {
+    crate::db::get_group(id)
+        .await?
+        .filter(|group| group.active)
+        .ok_or_else(|| failure::format_err!("Group not found"))
+}
+
+// groups have no admins: any member may add or remove members, and every member applies the
+// changes it is sent by another member
+pub async fn create(name: String, members: Vec<PublicKey>) -> Result<Uuid, Error> {
+    let local = local();
+    let mut unique: Vec<PublicKey> = Vec::with_capacity(members.len());
+    for member in members {
+        if member != local && !unique.contains(&member) {
+            unique.push(member);
+        }
+    }
+    let members = unique;
+    if members.is_empty() {
+        failure::bail!("A group needs members");
+    }
+    if name.len() > MAX_NAME_LEN {
+        failure::bail!("Group name too long");
+    }
+    if members.len() > MAX_MEMBERS {
+        failure::bail!("Too many group members");
+    }
+    let id = Uuid::new_v4();
+    crate::db::save_group(id, name.clone(), members.clone(), true).await?;
+    let mut everyone = members.clone();
+    everyone.push(local);
+    announce(
+        id,
+        Content::Group {
+            op: GroupOp::Create,
+            name,
+            members: everyone,
+        },
+        &members,
+    )
+    .await;
+    Ok(id)
+}
+
+pub async fn add(id: Uuid, added: Vec<PublicKey>) -> Result<(), Error> {
+    let local = local();
+    let mut group = get_active(id).await?;
+    for member in added {
+        if member != local && !group.members.contains(&member) {
+            group.members.push(member);
+        }
+    }
+    if group.members.len() > MAX_MEMBERS {
+        failure::bail!("Too many group members");
+    }
+    crate::db::save_group(id, group.name.clone(), group.members.clone(), true).await?;
+    let mut everyone = group.members.clone();
+    everyone.push(local);
+    announce(
+        id,
+        Content::Group {
+            op: GroupOp::Add,
+            name: group.name,
+            members: everyone,
+        },
+        &group.members,
+    )
+    .await;
+    Ok(())
+}
+
+// removed members are told too, so they stop expecting the group's messages
+pub async fn remove(id: Uuid, removed: Vec<PublicKey>) -> Result<(), Error> {
+    let group = get_active(id).await?;
+    announce(
+        id,
+        Content::Group {
+            op: GroupOp::Remove,
+            name: group.name.clone(),
+            members: removed.clone(),
+        },
+        &group.members,
+    )
+    .await;
+    crate::db::save_group(
+        id,
+        group.name,
+        group
+            .members
+            .into_iter()
+            .filter(|m| !removed.contains(m))
+            .collect(),
+        true,
+    )
+    .await
+}
+
+pub async fn leave(id: Uuid) -> Result<(), Error> {
+    let group = get_active(id).await?;
+    announce(
+        id,
+        Content::Group {
+            op: GroupOp::Leave,
+            name: group.name.clone(),
+            members: Vec::new(),
+        },
+        &group.members,
+    )
+    .await;
+    crate::db::save_group(id, group.name, group.members, false).await
+}
+
+pub async fn send(
+    tracking_id: Option<Uuid>,
+    id: Uuid,
+    reply_to: Option<Uuid>,
+    text: String,
+) -> Result<(), Error> {
+    let group = get_active(id).await?;
+    // stored once, against our own key, with a delivery per member
+    let msg = NewOutboundMessage {
+        tracking_id,
+        to: local(),
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to,
+        group: Some(id),
+        time: crate::util::now(),
+        ttl: None,
+        content: Content::Text(text),
+    };
+    let message_id = crate::db::save_out_group_message(msg.clone(), group.members.clone()).await?;
+    for (member, res) in fan_out(&msg, &group.members).await {
+        record(message_id, member, res, 1, msg.time).await?;
+    }
+    if group.members.is_empty() {
+        crate::db::set_status(message_id, MessageStatus::Failed).await?;
+    }
+    Ok(())
+}
+
+/// Tries a member the group message has not reached yet again.
+pub async fn retry(queued: &Queued) -> Result<(), Error> {
+    let res = deliver(&queued.message).await;
+    record(
+        queued.id,
+        queued.message.to,
+        res,
+        queued.attempts,
+        queued.queued_at,
+    )
+    .await
+}
+
+// as with direct messages, a member that could not be reached is left for the outbox to retry
+async fn record(
+    id: i64,
+    member: PublicKey,
+    res: Result<MessageStatus, Error>,
+    attempts: u32,
+    queued_at: i64,
+) -> Result<(), Error> {
+    match res {
+        Ok(status) => crate::db::set_delivery(id, member, status).await,
+        Err(e) => {
+            eprintln!(
+                "ERROR SENDING GROUP MESSAGE {} (ATTEMPT {}): {}",
+                id, attempts, e
+            );
+            let retry_at = crate::message::retry_at(&e, attempts, queued_at);
+            crate::db::save_failed_delivery(id, member, e.to_string(), retry_at).await
+        }
+    }
+}
+
+async fn announce(id: Uuid, content: Content, to: &[PublicKey]) {
+    let msg = NewOutboundMessage {
+        tracking_id: None,
+        to: local(),
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to: None,
+        group: Some(id),
+        time: crate::util::now(),
+        ttl: None,
+        content,
+    };
+    for (_, res) in fan_out(&msg, to).await {
+        if let Err(e) = res {
+            eprintln!("ERROR UPDATING GROUP MEMBER: {}", e);
+        }
+    }
+}
+
+async fn deliver(msg: &NewOutboundMessage) -> Result<MessageStatus, Error> {
+    if !crate::message::capabilities(&msg.to, false)
+        .await?
+        .has_feature("groups")
+    {
+        failure::bail!("Peer does not support groups");
+    }
+    crate::message::deliver(msg).await
+}
+
+async fn fan_out(
+    msg: &NewOutboundMessage,
+    to: &[PublicKey],
+) -> Vec<(PublicKey, Result<MessageStatus, Error>)> {
+    futures::future::join_all(to.iter().map(|member| {
+        let msg = NewOutboundMessage {
+            to: *member,
+            ..msg.clone()
+        };
+        async move {
+            let res = deliver(&msg).await;
+            (msg.to, res)
+        }
+    }))
+    .await
+}
+
+pub async fn receive(msg: NewInboundMessage, horizon: i64) -> Result<(), Error> {
+    match msg.content {
+        Content::Text(_) => {
+            crate::presence::clear_typing(&msg.from, crate::util::now());
+            crate::db::save_in_message(msg, horizon).await
+        }
+        Content::Group { .. } => crate::db::save_group_update(msg, horizon).await,
+        _ => failure::bail!("Unsupported group message"),
+    }
+}
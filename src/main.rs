@@ -1,5 +1,6 @@
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use ed25519_dalek::PublicKey;
 use failure::Error;
@@ -8,11 +9,19 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server};
 use uuid::Uuid;
 
+mod attachment;
+mod capabilities;
 mod db;
 mod delete;
+mod envelope;
+mod group;
 mod message;
 mod migrations;
+mod onion;
+mod presence;
 mod query;
+mod session;
+mod transport;
 mod util;
 mod wire;
 
@@ -21,6 +30,85 @@ mod wire;
 pub struct Config {
     pub password: String,
     pub address_private_key: String,
+    #[serde(default = "default_replay_window")]
+    pub replay_window: i64,
+    #[serde(default = "const_true")]
+    pub read_receipts: bool,
+    #[serde(default = "const_true")]
+    pub sessions: bool,
+    #[serde(default)]
+    pub padding: crate::wire::Padding,
+    #[serde(default = "default_clock_skew_window")]
+    pub clock_skew_window: i64,
+    #[serde(default = "default_max_future_skew")]
+    pub max_future_skew: i64,
+    #[serde(default = "default_max_peer_body")]
+    pub max_peer_body: u64,
+    #[serde(default = "default_max_api_body")]
+    pub max_api_body: u64,
+    #[serde(default = "default_max_attachment_body")]
+    pub max_attachment_body: u64,
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    #[serde(default = "default_retry_period")]
+    pub retry_period: i64,
+    #[serde(default)]
+    pub transport: crate::transport::TransportConfig,
+    #[serde(default = "default_proxy")]
+    pub proxy: String,
+    #[serde(default = "default_peer_port")]
+    pub peer_port: u16,
+    #[serde(default = "default_listen")]
+    pub listen: Vec<SocketAddr>,
+}
+
+const fn const_true() -> bool {
+    true
+}
+
+const fn default_replay_window() -> i64 {
+    60 * 60 * 24 * 7
+}
+
+const fn default_clock_skew_window() -> i64 {
+    60 * 5
+}
+
+const fn default_max_future_skew() -> i64 {
+    60 * 60 * 24
+}
+
+const fn default_max_peer_body() -> u64 {
+    crate::capabilities::MAX_MESSAGE_SIZE
+}
+
+const fn default_max_api_body() -> u64 {
+    1024 * 1024
+}
+
+// room for the opcode 4 header and the longest name
+const fn default_max_attachment_body() -> u64 {
+    crate::attachment::MAX_SIZE + 50 + 255
+}
+
+const fn default_read_timeout() -> u64 {
+    30
+}
+
+const fn default_retry_period() -> i64 {
+    60 * 60 * 24 * 3
+}
+
+fn default_proxy() -> String {
+    "socks5h://embassy:9050".to_owned()
+}
+
+const fn default_peer_port() -> u16 {
+    59001
+}
+
+fn default_listen() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([0, 0, 0, 0], 59001))]
 }
 
 lazy_static::lazy_static! {
@@ -34,8 +122,8 @@ lazy_static::lazy_static! {
         version[16..].clone_from_slice(&*PATCH);
         version
     };
-    pub static ref CONFIG: Config = serde_yaml::from_reader(std::fs::File::open("./start9/config.yaml").expect("./start9/config.yaml")).expect("./start9/config.yaml");
-    pub static ref PROXY: reqwest::Proxy = reqwest::Proxy::http("socks5h://embassy:9050").expect("PROXY");
+    pub static ref CONFIG: Config = load_config();
+    pub static ref PROXY: reqwest::Proxy = reqwest::Proxy::http(&CONFIG.proxy).expect("proxy");
     pub static ref SECKEY: ed25519_dalek::ExpandedSecretKey =
         ed25519_dalek::ExpandedSecretKey::from_bytes(
             &base32::decode(
@@ -45,17 +133,64 @@ lazy_static::lazy_static! {
         ).expect("TOR_KEY");
 }
 
-async fn get_bytes(body: &mut Body) -> Result<Vec<u8>, Error> {
-    let mut res = Vec::new();
-    while {
-        if let Some(chunk) = body.data().await {
-            res.extend_from_slice(&*chunk?);
-            true
-        } else {
-            false
+#[cfg(not(test))]
+fn load_config() -> Config {
+    serde_yaml::from_reader(std::fs::File::open("./start9/config.yaml").expect("./start9/config.yaml")).expect("./start9/config.yaml")
+}
+
+// tests talk to themselves over the loopback transport under a throwaway key
+#[cfg(test)]
+fn load_config() -> Config {
+    serde_yaml::from_str(&format!(
+        "password: test\naddress-private-key: {}\nsessions: false\ntransport:\n  type: loopback\n",
+        base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &ed25519_dalek::ExpandedSecretKey::from(&ed25519_dalek::SecretKey::generate(
+                &mut rand::rngs::OsRng
+            ))
+            .to_bytes(),
+        ),
+    ))
+    .expect("test config")
+}
+
+// fails with the status to answer instead: 413 past `limit`, which may depend on what has been
+// read so far, and 408 if the body is not complete within the read timeout
+async fn get_bytes(body: &mut Body, limit: impl Fn(&[u8]) -> u64) -> Result<Vec<u8>, u16> {
+    if body.size_hint().lower() > limit(&[]) {
+        return Err(413);
+    }
+    let read = async {
+        let mut res = Vec::new();
+        while let Some(chunk) = body.data().await {
+            res.extend_from_slice(&chunk.map_err(|_| 400u16)?);
+            if res.len() as u64 > limit(&res) {
+                return Err(413);
+            }
         }
-    } {}
-    Ok(res)
+        Ok(res)
+    };
+    tokio::time::timeout(Duration::from_secs(CONFIG.read_timeout), read)
+        .await
+        .map_err(|_| 408u16)?
+}
+
+// the largest body an authenticated POST may have, given its opcode if known yet
+fn api_limit(req_data: &[u8]) -> u64 {
+    match req_data.first() {
+        Some(4) => CONFIG.max_attachment_body,
+        Some(_) => CONFIG.max_api_body,
+        None => CONFIG.max_attachment_body.max(CONFIG.max_api_body),
+    }
+}
+
+// malformed pubkeys, uuids, text and numbers in what we were sent, rather than failures to serve it
+fn is_bad_request(e: &Error) -> bool {
+    e.downcast_ref::<crate::onion::InvalidAddress>().is_some()
+        || e.downcast_ref::<uuid::Error>().is_some()
+        || e.downcast_ref::<ed25519_dalek::SignatureError>().is_some()
+        || e.downcast_ref::<std::string::FromUtf8Error>().is_some()
+        || e.downcast_ref::<std::num::ParseIntError>().is_some()
 }
 
 async fn handle(req: Request<Body>) -> Result<Response<Body>, Error> {
@@ -68,7 +203,7 @@ async fn handle(req: Request<Body>) -> Result<Response<Body>, Error> {
         Err(e) => {
             eprintln!("ERROR: {}", e);
             Response::builder()
-                .status(500)
+                .status(if is_bad_request(e) { 400 } else { 500 })
                 .body(format!("{}", e).into())
                 .map_err(From::from)
         }
@@ -76,16 +211,24 @@ async fn handle(req: Request<Body>) -> Result<Response<Body>, Error> {
 }
 
 async fn handler(mut req: Request<Body>) -> Result<Response<Body>, Error> {
-    match req.method() {
-        &Method::POST => match req.headers().get("Authorization") {
+    match *req.method() {
+        Method::POST => match req.headers().get("Authorization") {
             Some(auth) => {
                 if auth
                     == &format!(
                         "Basic {}",
-                        base64::encode(&format!("me:{}", &*CONFIG.password))
+                        base64::encode(format!("me:{}", &*CONFIG.password))
                     )
                 {
-                    let req_data = get_bytes(req.body_mut()).await?;
+                    let req_data = match get_bytes(req.body_mut(), api_limit).await {
+                        Ok(req_data) => req_data,
+                        Err(status) => {
+                            return Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .map_err(From::from)
+                        }
+                    };
                     if req_data.len() < 33 {
                         Response::builder()
                             .status(400)
@@ -97,15 +240,199 @@ async fn handler(mut req: Request<Body>) -> Result<Response<Body>, Error> {
                                 tracking_id: Some(Uuid::from_slice(&req_data[1..17])?)
                                     .filter(|a| !a.is_nil()),
                                 to: PublicKey::from_bytes(&req_data[17..49])?,
-                                time: std::time::UNIX_EPOCH
-                                    .elapsed()
-                                    .map(|a| a.as_secs() as i64)
-                                    .unwrap_or_else(|a| a.duration().as_secs() as i64 * -1),
-                                content: String::from_utf8(req_data[49..].to_vec())?,
+                                nonce: rand::random(),
+                                uuid: Uuid::new_v4(),
+                                reply_to: None,
+                                group: None,
+                                time: crate::util::now(),
+                                ttl: None,
+                                content: crate::message::Content::Text(String::from_utf8(
+                                    req_data[49..].to_vec(),
+                                )?),
                             })
                             .await
                             .map(|_| Body::empty())
                             .map(Response::new),
+                            6 if req_data.len() >= 65 => {
+                                crate::message::send(crate::message::NewOutboundMessage {
+                                    tracking_id: Some(Uuid::from_slice(&req_data[1..17])?)
+                                        .filter(|a| !a.is_nil()),
+                                    to: PublicKey::from_bytes(&req_data[17..49])?,
+                                    nonce: rand::random(),
+                                    uuid: Uuid::new_v4(),
+                                    reply_to: Some(Uuid::from_slice(&req_data[49..65])?)
+                                        .filter(|a| !a.is_nil()),
+                                    group: None,
+                                    time: crate::util::now(),
+                                    ttl: None,
+                                    content: crate::message::Content::Text(String::from_utf8(
+                                        req_data[65..].to_vec(),
+                                    )?),
+                                })
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            7 | 8 if req_data.len() >= 41 => {
+                                let mut id = [0; 8];
+                                id.clone_from_slice(&req_data[33..41]);
+                                crate::message::edit(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    i64::from_be_bytes(id),
+                                    if req_data[0] == 7 {
+                                        Some(String::from_utf8(req_data[41..].to_vec())?)
+                                    } else {
+                                        None
+                                    },
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            9 if req_data.len() >= 41 => {
+                                let mut id = [0; 8];
+                                id.clone_from_slice(&req_data[33..41]);
+                                crate::message::react(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    i64::from_be_bytes(id),
+                                    String::from_utf8(req_data[41..].to_vec())?,
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            10 if req_data.len() >= 49 => crate::group::send(
+                                Some(Uuid::from_slice(&req_data[1..17])?).filter(|a| !a.is_nil()),
+                                Uuid::from_slice(&req_data[17..33])?,
+                                Some(Uuid::from_slice(&req_data[33..49])?).filter(|a| !a.is_nil()),
+                                String::from_utf8(req_data[49..].to_vec())?,
+                            )
+                            .await
+                            .map(|_| Body::empty())
+                            .map(Response::new),
+                            11 if req_data.len() >= 2 + req_data[1] as usize
+                                && (req_data.len() - 2 - req_data[1] as usize).is_multiple_of(32) =>
+                            {
+                                let name_end = 2 + req_data[1] as usize;
+                                crate::group::create(
+                                    String::from_utf8(req_data[2..name_end].to_vec())?,
+                                    req_data[name_end..]
+                                        .chunks(32)
+                                        .map(PublicKey::from_bytes)
+                                        .collect::<Result<_, _>>()?,
+                                )
+                                .await
+                                .map(|id| Body::from(id.as_bytes().to_vec()))
+                                .map(Response::new)
+                            }
+                            12 | 13 if (req_data.len() - 17).is_multiple_of(32) => {
+                                let id = Uuid::from_slice(&req_data[1..17])?;
+                                let members = req_data[17..]
+                                    .chunks(32)
+                                    .map(PublicKey::from_bytes)
+                                    .collect::<Result<_, _>>()?;
+                                if req_data[0] == 12 {
+                                    crate::group::add(id, members).await
+                                } else {
+                                    crate::group::remove(id, members).await
+                                }
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            14 if req_data.len() >= 35
+                                && req_data.len() >= 35 + req_data[33] as usize
+                                && [35, 67].iter().any(|len| {
+                                    req_data.len()
+                                        == len
+                                            + req_data[33] as usize
+                                            + req_data[34 + req_data[33] as usize] as usize
+                                }) =>
+                            {
+                                let name_end = 34 + req_data[33] as usize;
+                                let bio_end = name_end + 1 + req_data[name_end] as usize;
+                                crate::message::send_profile(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    String::from_utf8(req_data[34..name_end].to_vec())?,
+                                    Some(String::from_utf8(req_data[name_end + 1..bio_end].to_vec())?)
+                                        .filter(|bio| !bio.is_empty()),
+                                    if req_data.len() > bio_end {
+                                        let mut avatar = [0; 32];
+                                        avatar.clone_from_slice(&req_data[bio_end..]);
+                                        Some(avatar)
+                                    } else {
+                                        None
+                                    },
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            15 if req_data.len() == 41 => {
+                                let mut id = [0; 8];
+                                id.clone_from_slice(&req_data[33..41]);
+                                crate::message::resend(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    i64::from_be_bytes(id),
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            16 if req_data[1] > 0
+                                && req_data.len() >= 2 + 32 * req_data[1] as usize =>
+                            {
+                                let to_end = 2 + 32 * req_data[1] as usize;
+                                let to = req_data[2..to_end]
+                                    .chunks(32)
+                                    .map(PublicKey::from_bytes)
+                                    .collect::<Result<_, _>>()?;
+                                let text = String::from_utf8(req_data[to_end..].to_vec())?;
+                                let mut res = Vec::new();
+                                for (to, tracking_id, status) in
+                                    crate::message::broadcast(to, text).await
+                                {
+                                    res.extend_from_slice(to.as_bytes());
+                                    res.extend_from_slice(tracking_id.as_bytes());
+                                    let mut error = match status {
+                                        Ok(status) => {
+                                            res.push(status as u8);
+                                            String::new()
+                                        }
+                                        Err(e) => {
+                                            res.push(u8::MAX);
+                                            e.to_string()
+                                        }
+                                    };
+                                    crate::util::truncate(&mut error, u16::MAX as usize);
+                                    res.extend_from_slice(&u16::to_be_bytes(error.len() as u16));
+                                    res.extend_from_slice(error.as_bytes());
+                                }
+                                Ok(Response::new(Body::from(res)))
+                            }
+                            17 if req_data.len() >= 57 => {
+                                let mut send_at = [0; 8];
+                                send_at.clone_from_slice(&req_data[49..57]);
+                                crate::message::schedule(
+                                    crate::message::NewOutboundMessage {
+                                        tracking_id: Some(Uuid::from_slice(&req_data[1..17])?)
+                                            .filter(|a| !a.is_nil()),
+                                        to: PublicKey::from_bytes(&req_data[17..49])?,
+                                        nonce: rand::random(),
+                                        uuid: Uuid::new_v4(),
+                                        reply_to: None,
+                                        group: None,
+                                        time: crate::util::now(),
+                                        ttl: None,
+                                        content: crate::message::Content::Text(String::from_utf8(
+                                            req_data[57..].to_vec(),
+                                        )?),
+                                    },
+                                    i64::from_be_bytes(send_at),
+                                )
+                                .await
+                                .map(|id| Body::from(i64::to_be_bytes(id).to_vec()))
+                                .map(Response::new)
+                            }
                             1 => crate::db::save_user(
                                 PublicKey::from_bytes(&req_data[1..33])?,
                                 String::from_utf8(req_data[33..].to_vec())?,
@@ -113,6 +440,66 @@ async fn handler(mut req: Request<Body>) -> Result<Response<Body>, Error> {
                             .await
                             .map(|_| Body::empty())
                             .map(Response::new),
+                            2 if req_data.len() == 34 => crate::db::set_read_receipts(
+                                PublicKey::from_bytes(&req_data[1..33])?,
+                                req_data[33] != 0,
+                            )
+                            .await
+                            .map(|_| Body::empty())
+                            .map(Response::new),
+                            18 if req_data.len() == 37 => {
+                                let mut ttl = [0; 4];
+                                ttl.clone_from_slice(&req_data[33..37]);
+                                crate::db::set_ttl(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    Some(u32::from_be_bytes(ttl)).filter(|ttl| *ttl != 0),
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            3 if req_data.len() == 34 => match crate::presence::Signal::from_u8(
+                                req_data[33],
+                            ) {
+                                Some(signal) => crate::message::send_signal(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    signal,
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new),
+                                None => Response::builder()
+                                    .status(400)
+                                    .body(Body::empty())
+                                    .map_err(From::from),
+                            },
+                            4 if req_data.len() >= 50
+                                && req_data.len() >= 50 + req_data[49] as usize =>
+                            {
+                                let name_end = 50 + req_data[49] as usize;
+                                crate::attachment::send(
+                                    Some(Uuid::from_slice(&req_data[1..17])?)
+                                        .filter(|a| !a.is_nil()),
+                                    PublicKey::from_bytes(&req_data[17..49])?,
+                                    None,
+                                    String::from_utf8(req_data[50..name_end].to_vec())?,
+                                    req_data[name_end..].to_vec(),
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
+                            5 if req_data.len() == 41 => {
+                                let mut id = [0; 8];
+                                id.clone_from_slice(&req_data[33..41]);
+                                crate::attachment::resume(
+                                    PublicKey::from_bytes(&req_data[1..33])?,
+                                    i64::from_be_bytes(id),
+                                )
+                                .await
+                                .map(|_| Body::empty())
+                                .map(Response::new)
+                            }
                             _ => Response::builder()
                                 .status(400)
                                 .body(Body::empty())
@@ -126,17 +513,27 @@ async fn handler(mut req: Request<Body>) -> Result<Response<Body>, Error> {
                         .map_err(From::from)
                 }
             }
-            _ => crate::message::receive(&get_bytes(req.body_mut()).await?)
+            _ => match get_bytes(req.body_mut(), |_| CONFIG.max_peer_body).await {
+                Ok(req_data) => crate::message::receive(&req_data)
+                    .await
+                    .map(Body::from)
+                    .map(Response::new),
+                Err(status) => Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .map_err(From::from),
+            },
+        },
+        Method::GET => match (req.headers().get("Authorization"), req.uri().query()) {
+            (_, Some("type=prekey")) => crate::session::prekey_bundle()
                 .await
-                .map(|_| Body::empty())
+                .map(Body::from)
                 .map(Response::new),
-        },
-        &Method::GET => match (req.headers().get("Authorization"), req.uri().query()) {
             (Some(auth), Some(query))
                 if auth
                     == &format!(
                         "Basic {}",
-                        base64::encode(&format!("me:{}", &*CONFIG.password))
+                        base64::encode(format!("me:{}", &*CONFIG.password))
                     ) =>
             {
                 match serde_urlencoded::from_str(query) {
@@ -150,18 +547,22 @@ async fn handler(mut req: Request<Body>) -> Result<Response<Body>, Error> {
                         .map_err(From::from),
                 }
             }
-            (_, None) => Ok(Response::new(Body::from(&VERSION[..]))),
+            (_, None) => {
+                let mut res = VERSION.to_vec();
+                res.extend(crate::capabilities::Capabilities::local().encode());
+                Ok(Response::new(Body::from(res)))
+            }
             _ => Response::builder()
                 .status(401)
                 .body(Body::empty())
                 .map_err(From::from),
         },
-        &Method::DELETE => match (req.headers().get("Authorization"), req.uri().query()) {
+        Method::DELETE => match (req.headers().get("Authorization"), req.uri().query()) {
             (Some(auth), Some(query))
                 if auth
                     == &format!(
                         "Basic {}",
-                        base64::encode(&format!("me:{}", &*CONFIG.password))
+                        base64::encode(format!("me:{}", &*CONFIG.password))
                     ) =>
             {
                 match serde_urlencoded::from_str(query) {
@@ -215,8 +616,14 @@ pub struct Metric<'a> {
 
 #[tokio::main(worker_threads = 4)]
 async fn main() {
-    println!("USING PROXY: {:?}", &*PROXY);
-    &*CONFIG;
+    lazy_static::initialize(&CONFIG);
+    match &CONFIG.transport {
+        crate::transport::TransportConfig::Tor => {
+            println!("USING PROXY: {:?}", &*PROXY);
+            println!("PEER PORT: {}", CONFIG.peer_port);
+        }
+        transport => println!("USING TRANSPORT: {:?}", transport),
+    }
     let data = Data {
         password: Metric {
             value_type: "string",
@@ -235,18 +642,33 @@ async fn main() {
     std::fs::rename("./start9/.stats.yaml.tmp", "./start9/stats.yaml").unwrap();
 
     let mig = crate::migrations::migrate();
-    // Construct our SocketAddr to listen on...
-    let addr = SocketAddr::from(([0, 0, 0, 0], 59001));
-
-    // And a MakeService to handle each connection...
-    let make_service = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
 
-    // Then bind and serve...
-    let server = Server::bind(&addr).serve(make_service);
+    // Bind and serve on every configured address...
+    let servers = CONFIG
+        .listen
+        .iter()
+        .map(|addr| {
+            println!("LISTENING ON: {}", addr);
+            // And a MakeService to handle each connection...
+            let make_service =
+                make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+            Server::try_bind(addr)
+                .unwrap_or_else(|e| panic!("bind {}: {}", addr, e))
+                .http1_header_read_timeout(Duration::from_secs(CONFIG.read_timeout))
+                .serve(make_service)
+        })
+        .collect::<Vec<_>>();
 
     mig.await.expect("migration");
+    crate::db::reset_outbox(crate::util::now())
+        .await
+        .expect("outbox");
+    tokio::spawn(crate::message::outbox());
+    tokio::spawn(crate::message::reaper());
     // And run forever...
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    for res in futures::future::join_all(servers).await {
+        if let Err(e) = res {
+            eprintln!("server error: {}", e);
+        }
     }
 }
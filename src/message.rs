@@ -1,52 +1,693 @@
+use curve25519_dalek::montgomery::MontgomeryPoint;
 use ed25519_dalek::PublicKey;
-use failure::Error;
+use failure::{Error, Fail};
 use uuid::Uuid;
 
+use crate::capabilities::Capabilities;
+use crate::db::MessageStatus;
+use crate::group::GroupOp;
+use crate::presence::Signal;
+use crate::transport::{url, TRANSPORT};
+
+pub const MAX_EMOJI_LEN: usize = 64;
+pub const MAX_PROFILE_NAME_LEN: usize = 255;
+pub const MAX_BIO_LEN: usize = 255;
+
+// how a peer rejects a nonce it has already seen
+const REPLAYED: &str = "Replayed message";
+const RETRY_BASE: i64 = 30;
+const RETRY_MAX: i64 = 60 * 60;
+const OUTBOX_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+const REAPER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const OUTBOX_BATCH: usize = 32;
+const BROADCAST_PARALLELISM: usize = 8;
+
+/// Why a timed message was refused: the peer cannot honour its timer.
+#[derive(Debug)]
+pub struct NoDisappearing;
+
+impl std::fmt::Display for NoDisappearing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer does not support disappearing messages")
+    }
+}
+
+impl Fail for NoDisappearing {}
+
+#[derive(Clone, Debug)]
+pub enum Content {
+    Text(String),
+    ReadReceipt(Vec<[u8; 16]>),
+    Presence(Signal),
+    Attachment {
+        name: String,
+        size: u64,
+        hash: [u8; 32],
+    },
+    Chunk {
+        attachment: [u8; 16],
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Edit {
+        target: Uuid,
+        text: String,
+    },
+    Retract {
+        target: Uuid,
+    },
+    Reaction {
+        target: Uuid,
+        emoji: String,
+    },
+    Group {
+        op: GroupOp,
+        name: String,
+        members: Vec<PublicKey>,
+    },
+    /// How the sender describes themselves; the avatar is only referenced by its hash.
+    Profile {
+        name: String,
+        bio: Option<String>,
+        avatar: Option<[u8; 32]>,
+    },
+}
+
+impl Content {
+    /// The kind of this content on the wire, and its `content_type` in `messages`.
+    pub fn kind(&self) -> u8 {
+        match self {
+            Content::Text(_) => 0,
+            Content::ReadReceipt(_) => 1,
+            Content::Presence(_) => 2,
+            Content::Attachment { .. } => 3,
+            Content::Chunk { .. } => 4,
+            Content::Edit { .. } => 5,
+            Content::Retract { .. } => 6,
+            Content::Reaction { .. } => 7,
+            Content::Group { .. } => 8,
+            Content::Profile { .. } => 9,
+        }
+    }
+}
+
+pub enum Inbound {
+    Message(NewInboundMessage),
+    /// A kind newer than this version understands.
+    Unsupported {
+        from: PublicKey,
+        nonce: [u8; 16],
+        uuid: Uuid,
+        time: i64,
+        kind: u8,
+        fallback: Option<String>,
+    },
+}
+
+impl Inbound {
+    pub fn from(&self) -> &PublicKey {
+        match self {
+            Inbound::Message(msg) => &msg.from,
+            Inbound::Unsupported { from, .. } => from,
+        }
+    }
+}
+
 pub struct NewInboundMessage {
     pub from: PublicKey,
+    pub nonce: [u8; 16],
+    pub uuid: Uuid,
+    pub reply_to: Option<Uuid>,
+    pub group: Option<Uuid>,
     pub time: i64,
-    pub content: String,
+    /// Seconds after `time` until the message disappears.
+    pub ttl: Option<u32>,
+    pub content: Content,
 }
 
+#[derive(Clone)]
 pub struct NewOutboundMessage {
     pub tracking_id: Option<Uuid>,
     pub to: PublicKey,
+    pub nonce: [u8; 16],
+    pub uuid: Uuid,
+    pub reply_to: Option<Uuid>,
+    pub group: Option<Uuid>,
     pub time: i64,
-    pub content: String,
-}
-
-lazy_static::lazy_static! {
-    static ref CLIENT: reqwest::Client = reqwest::Client::builder().proxy(crate::PROXY.clone()).build().expect("CLIENT");
-}
-
-pub async fn send(msg: NewOutboundMessage) -> Result<(), Error> {
-    use sha3::{Digest, Sha3_256};
-
-    let mut hasher = Sha3_256::new();
-    hasher.update(b".onion checksum");
-    hasher.update(msg.to.as_bytes());
-    hasher.update(&[3]);
-    let mut onion = Vec::with_capacity(35);
-    onion.extend_from_slice(msg.to.as_bytes());
-    onion.extend_from_slice(&hasher.finalize()[..2]);
-    onion.push(3);
-    let onion_str =
-        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &onion).to_lowercase();
-    let res = CLIENT
-        .post(&format!("http://{}.onion:59001", onion_str))
-        .body(crate::wire::encode(&*crate::SECKEY, &msg)?)
-        .send()
+    pub ttl: Option<u32>,
+    pub content: Content,
+}
+
+pub async fn capabilities(pubkey: &PublicKey, refresh: bool) -> Result<Capabilities, Error> {
+    let now = crate::util::now();
+    if !refresh {
+        if let Some(caps) =
+            crate::db::get_capabilities(*pubkey, now - crate::capabilities::TTL).await?
+        {
+            return Ok(caps);
+        }
+    }
+    let (status, version) = TRANSPORT.get(pubkey, None).await?;
+    if !status.is_success() {
+        failure::bail!("{}", status.canonical_reason().unwrap_or("UNKNOWN ERROR"))
+    }
+    let caps = Capabilities::parse(
+        version
+            .get(crate::VERSION.len()..)
+            .ok_or_else(|| failure::format_err!("Invalid version response"))?,
+    )?;
+    crate::db::save_capabilities(*pubkey, caps.clone(), now).await?;
+    Ok(caps)
+}
+
+// the message is stored before the first attempt, so a peer that is offline only delays it:
+// `Pending` means it is queued for another attempt
+pub async fn send(mut msg: NewOutboundMessage) -> Result<MessageStatus, Error> {
+    msg.ttl = crate::db::get_ttl(msg.to).await?;
+    let id = crate::db::save_out_message(msg.clone()).await?;
+    attempt(id, &msg, 1, msg.time).await
+}
+
+pub async fn attempt(
+    id: i64,
+    msg: &NewOutboundMessage,
+    attempts: u32,
+    queued_at: i64,
+) -> Result<MessageStatus, Error> {
+    match deliver(msg).await {
+        Ok(status) => {
+            crate::db::set_status(id, status).await?;
+            // the peer now expects the attachment's chunks
+            if let Content::Attachment { .. } = msg.content {
+                let attachment = crate::db::get_attachment_id(id)
+                    .await?
+                    .ok_or_else(|| failure::format_err!("Attachment not found"))?;
+                crate::attachment::resume(msg.to, attachment).await?;
+            }
+            Ok(status)
+        }
+        Err(e) => {
+            eprintln!("ERROR SENDING MESSAGE {} (ATTEMPT {}): {}", id, attempts, e);
+            let retry_at = retry_at(&e, attempts, queued_at);
+            crate::db::save_failed_attempt(id, e.to_string(), retry_at).await?;
+            Ok(if retry_at.is_some() {
+                MessageStatus::Pending
+            } else {
+                MessageStatus::Failed
+            })
+        }
+    }
+}
+
+// the outbox sends it once `send_at` comes
+pub async fn schedule(mut msg: NewOutboundMessage, send_at: i64) -> Result<i64, Error> {
+    msg.ttl = crate::db::get_ttl(msg.to).await?;
+    crate::db::save_scheduled_message(msg, send_at).await
+}
+
+/// Sends the same text to each recipient, a few at a time, answering in the order given.
+pub async fn broadcast(
+    to: Vec<PublicKey>,
+    text: String,
+) -> Vec<(PublicKey, Uuid, Result<MessageStatus, Error>)> {
+    use futures::stream::StreamExt;
+
+    futures::stream::iter(to.into_iter().map(|to| {
+        let tracking_id = Uuid::new_v4();
+        let text = text.clone();
+        async move {
+            let res = send(NewOutboundMessage {
+                tracking_id: Some(tracking_id),
+                to,
+                nonce: rand::random(),
+                uuid: Uuid::new_v4(),
+                reply_to: None,
+                group: None,
+                time: crate::util::now(),
+                ttl: None,
+                content: Content::Text(text),
+            })
+            .await;
+            (to, tracking_id, res)
+        }
+    }))
+    .buffered(BROADCAST_PARALLELISM)
+    .collect()
+    .await
+}
+
+/// When to try again after the given failure, or `None` to give up.
+pub fn retry_at(e: &Error, attempts: u32, queued_at: i64) -> Option<i64> {
+    Some(crate::util::now() + backoff(attempts))
+        .filter(|at| *at < queued_at + crate::CONFIG.retry_period)
+        // retrying will not teach the peer to honour the timer
+        .filter(|_| e.downcast_ref::<NoDisappearing>().is_none())
+}
+
+// exponential, with up to half of it left to jitter so retries to one peer spread out
+fn backoff(attempts: u32) -> i64 {
+    use rand::Rng;
+
+    let delay = RETRY_BASE
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(RETRY_MAX);
+    rand::thread_rng().gen_range(delay / 2, delay + 1)
+}
+
+/// Retries pending messages, and group members still to be reached, as they come due; runs for
+/// the life of the server.
+pub async fn outbox() {
+    loop {
+        let now = crate::util::now();
+        let mut due = Vec::new();
+        for claimed in [
+            crate::db::claim_due_messages(now, OUTBOX_BATCH).await,
+            crate::db::claim_due_deliveries(now, OUTBOX_BATCH).await,
+        ] {
+            match claimed {
+                Ok(claimed) => due.extend(claimed),
+                Err(e) => eprintln!("ERROR READING OUTBOX: {}", e),
+            }
+        }
+        futures::future::join_all(due.iter().map(|queued| async move {
+            let res = match queued.message.group {
+                Some(_) => crate::group::retry(queued).await,
+                None => attempt(
+                    queued.id,
+                    &queued.message,
+                    queued.attempts,
+                    queued.queued_at,
+                )
+                .await
+                .map(|_| ()),
+            };
+            if let Err(e) = res {
+                eprintln!("ERROR RETRYING MESSAGE {}: {}", queued.id, e);
+            }
+        }))
+        .await;
+        tokio::time::sleep(OUTBOX_INTERVAL).await;
+    }
+}
+
+/// Deletes disappearing messages as their timers run out.
+pub async fn reaper() {
+    loop {
+        if let Err(e) = crate::db::del_expired(crate::util::now()).await {
+            eprintln!("ERROR DELETING EXPIRED MESSAGES: {}", e);
+        }
+        tokio::time::sleep(REAPER_INTERVAL).await;
+    }
+}
+
+pub async fn resend(to: PublicKey, id: i64) -> Result<(), Error> {
+    crate::db::requeue(to, id, crate::util::now()).await
+}
+
+pub async fn deliver(msg: &NewOutboundMessage) -> Result<MessageStatus, Error> {
+    let receipt = match exchange(msg).await {
+        Ok(receipt) => receipt,
+        // retries reuse the nonce, so the peer already has this message from an earlier attempt
+        // whose answer we never saw
+        Err(e) if e.to_string() == REPLAYED => return Ok(MessageStatus::Delivered),
+        Err(e) => return Err(e),
+    };
+    Ok(receipt_status(msg, &receipt))
+}
+
+// the peer took the message either way, but only a receipt that checks out proves delivery
+fn receipt_status(msg: &NewOutboundMessage, receipt: &[u8]) -> MessageStatus {
+    if receipt.is_empty() {
+        return MessageStatus::Sent;
+    }
+    match crate::wire::verify_receipt(
+        &msg.to,
+        &PublicKey::from(&*crate::SECKEY),
+        &msg.nonce,
+        receipt,
+    ) {
+        Ok(()) => MessageStatus::Delivered,
+        Err(e) => {
+            eprintln!("INVALID RECEIPT FROM {}: {}", url(&msg.to), e);
+            MessageStatus::Sent
+        }
+    }
+}
+
+pub async fn exchange(msg: &NewOutboundMessage) -> Result<Vec<u8>, Error> {
+    let url = url(&msg.to);
+    let mut caps = capabilities(&msg.to, false).await?;
+    if msg.ttl.is_some() && !caps.has_feature("disappearing") {
+        // the peer would keep a message the user expects to disappear
+        caps = capabilities(&msg.to, true).await?;
+        if !caps.has_feature("disappearing") {
+            return Err(NoDisappearing.into());
+        }
+    }
+    let mut res = TRANSPORT.post(&msg.to, encode(&caps, msg).await?).await?;
+    if res.0.is_server_error() && String::from_utf8_lossy(&res.1).contains("Unsupported version") {
+        // our cached view of the peer is stale
+        let version = caps.best_version()?;
+        caps = capabilities(&msg.to, true).await?;
+        if caps.best_version()? != version {
+            res = TRANSPORT.post(&msg.to, encode(&caps, msg).await?).await?;
+        }
+    }
+    if res.0.is_server_error() && crate::session::is_stale(&String::from_utf8_lossy(&res.1)) {
+        crate::db::del_sessions(msg.to).await?;
+        res = TRANSPORT.post(&msg.to, encode(&caps, msg).await?).await?;
+    }
+    let (status, body) = res;
+    if status.is_server_error() && body == REPLAYED.as_bytes() {
+        failure::bail!("{}", REPLAYED);
+    }
+    if !status.is_success() {
+        eprintln!("ERROR SENDING TO {}", url);
+        failure::bail!("{}", status.canonical_reason().unwrap_or("UNKNOWN ERROR"))
+    }
+    Ok(body)
+}
+
+async fn encode(caps: &Capabilities, msg: &NewOutboundMessage) -> Result<Vec<u8>, Error> {
+    let res = match caps.best_version()? {
+        2 => crate::session::encode(msg, caps.max_message_size).await?,
+        version => crate::wire::encode(
+            &crate::SECKEY,
+            version,
+            msg,
+            None,
+            crate::CONFIG.padding,
+            caps.max_message_size,
+        )?,
+    };
+    if res.len() as u64 > caps.max_message_size {
+        failure::bail!("Message exceeds peer's maximum size");
+    }
+    Ok(res)
+}
+
+pub async fn prekey(pubkey: &PublicKey) -> Result<MontgomeryPoint, Error> {
+    let (status, bundle) = TRANSPORT.get(pubkey, Some("type=prekey")).await?;
+    if !status.is_success() {
+        failure::bail!("{}", status.canonical_reason().unwrap_or("UNKNOWN ERROR"))
+    }
+    crate::session::verify_prekey_bundle(pubkey, &bundle)
+}
+
+pub async fn receive(msg: &[u8]) -> Result<Vec<u8>, Error> {
+    let horizon = crate::util::now() - crate::CONFIG.replay_window;
+    let msg = match crate::session::parse(msg).await? {
+        Inbound::Message(msg) => msg,
+        Inbound::Unsupported {
+            from,
+            nonce,
+            uuid,
+            time,
+            kind,
+            fallback,
+        } => {
+            if time < horizon {
+                failure::bail!("Message too old");
+            }
+            if time > crate::util::now() + crate::CONFIG.max_future_skew {
+                failure::bail!("Message from the future");
+            }
+            crate::db::save_unsupported(from, nonce, uuid, time, kind, fallback, horizon).await?;
+            return Ok(crate::wire::encode_receipt(&crate::SECKEY, &from, &nonce));
+        }
+    };
+    if msg.time < horizon {
+        failure::bail!("Message too old");
+    }
+    let now = crate::util::now();
+    // within the limit, skewed clocks are only flagged to the client
+    if msg.time > now + crate::CONFIG.max_future_skew {
+        failure::bail!("Message from the future");
+    }
+    if let Content::Presence(signal) = msg.content {
+        // ephemeral: never persisted, so replays are only bounded by the window
+        if (msg.time - now).abs() > crate::presence::WINDOW {
+            failure::bail!("Stale presence signal");
+        }
+        crate::presence::update(&msg.from, signal, now);
+        return Ok(Vec::new());
+    }
+    if let Content::Chunk {
+        attachment,
+        offset,
+        data,
+    } = msg.content
+    {
+        let received = crate::db::save_chunk(
+            msg.from, msg.nonce, msg.time, attachment, offset, data, horizon,
+        )
+        .await?;
+        return Ok(u64::to_be_bytes(received).to_vec());
+    }
+    let receipt = crate::wire::encode_receipt(&crate::SECKEY, &msg.from, &msg.nonce);
+    if msg.group.is_some() {
+        crate::group::receive(msg, horizon).await?;
+        return Ok(receipt);
+    }
+    match msg.content {
+        Content::Text(_) => {
+            crate::presence::clear_typing(&msg.from, now);
+            crate::db::save_in_message(msg, horizon).await?
+        }
+        Content::ReadReceipt(nonces) => {
+            crate::db::save_read_receipt(msg.from, msg.nonce, msg.time, nonces, horizon).await?
+        }
+        Content::Attachment { size, .. } => {
+            if size > crate::attachment::MAX_SIZE {
+                failure::bail!("Attachment too large");
+            }
+            crate::presence::clear_typing(&msg.from, now);
+            crate::db::save_in_attachment(msg, horizon).await?
+        }
+        Content::Edit { target, text } => {
+            crate::db::save_edit(msg.from, msg.nonce, msg.time, target, text, horizon).await?
+        }
+        Content::Retract { target } => {
+            crate::db::save_retract(msg.from, msg.nonce, msg.time, target, horizon).await?
+        }
+        Content::Reaction { target, emoji } => {
+            if emoji.len() > MAX_EMOJI_LEN {
+                failure::bail!("Invalid reaction");
+            }
+            crate::db::save_reaction(msg.from, msg.nonce, msg.time, target, emoji, horizon).await?
+        }
+        Content::Profile { name, bio, avatar } => {
+            if !valid_profile(&name, &bio) {
+                failure::bail!("Invalid profile");
+            }
+            crate::db::save_profile(msg.from, msg.nonce, msg.time, name, bio, avatar, horizon)
+                .await?
+        }
+        Content::Group { .. } => failure::bail!("Group update without a group"),
+        Content::Presence(_) | Content::Chunk { .. } => (),
+    }
+    Ok(receipt)
+}
+
+// edits and retractions are only applied locally once the peer has accepted them
+pub async fn edit(to: PublicKey, id: i64, text: Option<String>) -> Result<(), Error> {
+    if !capabilities(&to, false).await?.has_feature("edits") {
+        failure::bail!("Peer does not support edits");
+    }
+    let target = crate::db::get_out_message_uuid(to, id)
         .await?
-        .status();
-    if !res.is_success() {
-        eprintln!("ERROR SENDING TO http://{}.onion:59001", onion_str);
-        failure::bail!("{}", res.canonical_reason().unwrap_or("UNKNOWN ERROR"))
+        .ok_or_else(|| failure::format_err!("Message not found"))?;
+    let time = crate::util::now();
+    deliver(&NewOutboundMessage {
+        tracking_id: None,
+        to,
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to: None,
+        group: None,
+        time,
+        ttl: None,
+        content: match &text {
+            Some(text) => Content::Edit {
+                target,
+                text: text.clone(),
+            },
+            None => Content::Retract { target },
+        },
+    })
+    .await?;
+    match text {
+        Some(text) => crate::db::edit_out_message(to, target, text, time).await,
+        None => crate::db::retract_out_message(to, target).await,
     }
-    crate::db::save_out_message(msg).await?;
+}
+
+pub fn send_read_receipts(to: PublicKey, nonces: Vec<[u8; 16]>) {
+    if !crate::CONFIG.read_receipts || nonces.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(e) = async {
+            if !crate::db::read_receipts_enabled(to).await?
+                || !capabilities(&to, false).await?.has_feature("read-receipts")
+            {
+                return Ok(());
+            }
+            deliver(&NewOutboundMessage {
+                tracking_id: None,
+                to,
+                nonce: rand::random(),
+                uuid: Uuid::new_v4(),
+                reply_to: None,
+                group: None,
+                time: crate::util::now(),
+                ttl: None,
+                content: Content::ReadReceipt(nonces),
+            })
+            .await
+            .map(|_| ())
+        }
+        .await
+        {
+            eprintln!("ERROR SENDING READ RECEIPTS: {}", e);
+        }
+    });
+}
+
+pub async fn send_signal(to: PublicKey, signal: Signal) -> Result<(), Error> {
+    if !capabilities(&to, false).await?.has_feature("presence") {
+        return Ok(());
+    }
+    deliver(&NewOutboundMessage {
+        tracking_id: None,
+        to,
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to: None,
+        group: None,
+        time: crate::util::now(),
+        ttl: None,
+        content: Content::Presence(signal),
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn react(to: PublicKey, id: i64, emoji: String) -> Result<(), Error> {
+    if emoji.len() > MAX_EMOJI_LEN {
+        failure::bail!("Invalid reaction");
+    }
+    if !capabilities(&to, false).await?.has_feature("reactions") {
+        failure::bail!("Peer does not support reactions");
+    }
+    let target = crate::db::get_message_uuid(to, id)
+        .await?
+        .ok_or_else(|| failure::format_err!("Message not found"))?;
+    let time = crate::util::now();
+    deliver(&NewOutboundMessage {
+        tracking_id: None,
+        to,
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to: None,
+        group: None,
+        time,
+        ttl: None,
+        content: Content::Reaction {
+            target,
+            emoji: emoji.clone(),
+        },
+    })
+    .await?;
+    crate::db::save_out_reaction(to, target, emoji, time).await
+}
+
+fn valid_profile(name: &str, bio: &Option<String>) -> bool {
+    name.len() <= MAX_PROFILE_NAME_LEN && bio.as_ref().is_none_or(|bio| bio.len() <= MAX_BIO_LEN)
+}
+
+// profiles are pushed rather than fetched, so a contact only learns of a change when we send it
+pub async fn send_profile(
+    to: PublicKey,
+    name: String,
+    bio: Option<String>,
+    avatar: Option<[u8; 32]>,
+) -> Result<(), Error> {
+    if !valid_profile(&name, &bio) {
+        failure::bail!("Invalid profile");
+    }
+    if !capabilities(&to, false).await?.has_feature("profiles") {
+        failure::bail!("Peer does not support profiles");
+    }
+    deliver(&NewOutboundMessage {
+        tracking_id: None,
+        to,
+        nonce: rand::random(),
+        uuid: Uuid::new_v4(),
+        reply_to: None,
+        group: None,
+        time: crate::util::now(),
+        ttl: None,
+        content: Content::Profile { name, bio, avatar },
+    })
+    .await?;
     Ok(())
 }
 
-pub async fn receive(msg: &[u8]) -> Result<(), Error> {
-    let msg = crate::wire::parse(msg)?;
-    crate::db::save_in_message(msg).await
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_within_its_jitter() {
+        // doubles with each attempt until it reaches the cap
+        let mut delay = RETRY_BASE;
+        for attempts in 1..24 {
+            for _ in 0..32 {
+                let backoff = backoff(attempts);
+                assert!(delay / 2 <= backoff && backoff <= delay);
+            }
+            delay = (delay * 2).min(RETRY_MAX);
+        }
+        assert_eq!(delay, RETRY_MAX);
+    }
+
+    #[test]
+    fn retry_at_gives_up_after_the_retry_period() {
+        let e = failure::format_err!("Connection refused");
+        let now = crate::util::now();
+        let at = retry_at(&e, 1, now).unwrap();
+        assert!(now + RETRY_BASE / 2 <= at && at <= crate::util::now() + RETRY_BASE);
+        assert!(retry_at(&e, 1, now - crate::CONFIG.retry_period).is_none());
+        assert!(retry_at(&e, 20, now - crate::CONFIG.retry_period + RETRY_MAX / 2).is_none());
+        assert!(retry_at(&NoDisappearing.into(), 1, now).is_none());
+    }
+
+    #[test]
+    fn only_a_valid_receipt_means_delivered() {
+        let peer = ed25519_dalek::ExpandedSecretKey::from(&ed25519_dalek::SecretKey::generate(
+            &mut rand::rngs::OsRng,
+        ));
+        let msg = NewOutboundMessage {
+            tracking_id: None,
+            to: PublicKey::from(&peer),
+            nonce: rand::random(),
+            uuid: Uuid::new_v4(),
+            reply_to: None,
+            group: None,
+            time: crate::util::now(),
+            ttl: None,
+            content: Content::Text("hello".to_owned()),
+        };
+        let local = PublicKey::from(&*crate::SECKEY);
+        let receipt = crate::wire::encode_receipt(&peer, &local, &msg.nonce);
+        assert_eq!(receipt_status(&msg, &receipt), MessageStatus::Delivered);
+        assert_eq!(receipt_status(&msg, &[]), MessageStatus::Sent);
+        // signed by someone other than the recipient
+        let forged = crate::wire::encode_receipt(&crate::SECKEY, &local, &msg.nonce);
+        assert_eq!(receipt_status(&msg, &forged), MessageStatus::Sent);
+        // for another message
+        let other = crate::wire::encode_receipt(&peer, &local, &rand::random());
+        assert_eq!(receipt_status(&msg, &other), MessageStatus::Sent);
+    }
 }
@@ -9,6 +9,22 @@ pub async fn migrate() -> Result<(), Error> {
         let conn = gconn.transaction()?;
         init(&conn)?;
         tracking_ids(&conn)?;
+        nonces(&conn)?;
+        capabilities(&conn)?;
+        status(&conn)?;
+        read_receipts(&conn)?;
+        attachments(&conn)?;
+        uuids(&conn)?;
+        edits(&conn)?;
+        reactions(&conn)?;
+        sessions(&conn)?;
+        content_types(&conn)?;
+        groups(&conn)?;
+        profiles(&conn)?;
+        received_at(&conn)?;
+        outbox(&conn)?;
+        disappearing(&conn)?;
+        group_outbox(&conn)?;
         conn.commit()?;
         Ok::<_, Error>(())
     })
@@ -84,3 +100,471 @@ pub fn tracking_ids(conn: &rusqlite::Transaction) -> Result<(), Error> {
     }
     Ok(())
 }
+
+pub fn nonces(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'nonces'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING nonces MIGRATION");
+        let q = "CREATE TABLE nonces (
+                        user_id BLOB NOT NULL,
+                        nonce BLOB NOT NULL,
+                        time INTEGER NOT NULL,
+                        PRIMARY KEY (user_id, nonce)
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX nonces_time_idx ON nonces(time)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('nonces')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn capabilities(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'capabilities'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING capabilities MIGRATION");
+        let q = "CREATE TABLE capabilities (
+                        user_id BLOB PRIMARY KEY,
+                        versions BLOB NOT NULL,
+                        max_message_size INTEGER NOT NULL,
+                        features TEXT NOT NULL,
+                        updated_at INTEGER NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('capabilities')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn status(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'status'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING status MIGRATION");
+        // messages were only ever saved once the peer accepted them
+        let q = "ALTER TABLE messages ADD status INTEGER NOT NULL DEFAULT 1";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "UPDATE messages SET status = 2 WHERE inbound";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('status')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn read_receipts(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'read_receipts'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING read_receipts MIGRATION");
+        let q = "ALTER TABLE messages ADD nonce BLOB";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD read_at INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX messages_nonce_idx ON messages(user_id, nonce)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE TABLE user_settings (
+                        user_id BLOB PRIMARY KEY,
+                        read_receipts BOOLEAN NOT NULL DEFAULT TRUE
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('read_receipts')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn attachments(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'attachments'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING attachments MIGRATION");
+        let q = "CREATE TABLE attachments (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        message_id INTEGER NOT NULL,
+                        user_id BLOB NOT NULL,
+                        nonce BLOB NOT NULL,
+                        inbound BOOLEAN NOT NULL,
+                        name TEXT NOT NULL,
+                        hash BLOB NOT NULL,
+                        size INTEGER NOT NULL,
+                        received INTEGER NOT NULL DEFAULT 0,
+                        complete BOOLEAN NOT NULL DEFAULT FALSE,
+                        data BLOB NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX attachments_message_id_idx ON attachments(message_id)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX attachments_nonce_idx ON attachments(user_id, nonce)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('attachments')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn uuids(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'uuids'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING uuids MIGRATION");
+        let q = "ALTER TABLE messages ADD uuid BLOB";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD reply_to BLOB";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        // older messages never crossed the wire with an id, so any unique one will do
+        let q = "UPDATE messages SET uuid = randomblob(16)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX messages_uuid_idx ON messages(user_id, uuid)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('uuids')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn edits(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'edits'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING edits MIGRATION");
+        let q = "ALTER TABLE messages ADD edited BOOLEAN NOT NULL DEFAULT FALSE";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD retracted BOOLEAN NOT NULL DEFAULT FALSE";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE TABLE edits (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        message_id INTEGER NOT NULL,
+                        content TEXT NOT NULL,
+                        time INTEGER NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX edits_message_id_idx ON edits(message_id)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('edits')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn reactions(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'reactions'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING reactions MIGRATION");
+        let q = "CREATE TABLE reactions (
+                        message_id INTEGER NOT NULL,
+                        reactor BLOB NOT NULL,
+                        emoji TEXT NOT NULL,
+                        time INTEGER NOT NULL,
+                        PRIMARY KEY (message_id, reactor)
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('reactions')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn sessions(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'sessions'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING sessions MIGRATION");
+        let q = "CREATE TABLE sessions (
+                        id BLOB PRIMARY KEY,
+                        user_id BLOB NOT NULL,
+                        state BLOB NOT NULL,
+                        updated_at INTEGER NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX sessions_user_id_idx ON sessions(user_id)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE TABLE prekeys (
+                        public BLOB PRIMARY KEY,
+                        secret BLOB NOT NULL,
+                        created_at INTEGER NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('sessions')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn content_types(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'content_types'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING content_types MIGRATION");
+        let q = "ALTER TABLE messages ADD COLUMN content_type INTEGER NOT NULL DEFAULT 0";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "UPDATE messages SET content_type = 3 WHERE id IN (SELECT message_id FROM attachments)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('content_types')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn groups(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'groups'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING groups MIGRATION");
+        let q = "CREATE TABLE groups (
+                        id BLOB PRIMARY KEY,
+                        name TEXT NOT NULL,
+                        active BOOLEAN NOT NULL DEFAULT TRUE,
+                        created_at INTEGER NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE TABLE group_members (
+                        group_id BLOB NOT NULL,
+                        user_id BLOB NOT NULL,
+                        PRIMARY KEY (group_id, user_id)
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE TABLE deliveries (
+                        message_id INTEGER NOT NULL,
+                        user_id BLOB NOT NULL,
+                        status INTEGER NOT NULL,
+                        PRIMARY KEY (message_id, user_id)
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD COLUMN group_id BLOB";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX messages_group_id_idx ON messages(group_id)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('groups')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn profiles(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'profiles'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING profiles MIGRATION");
+        let q = "CREATE TABLE profiles (
+                        user_id BLOB PRIMARY KEY,
+                        name TEXT NOT NULL,
+                        bio TEXT,
+                        avatar BLOB,
+                        time INTEGER NOT NULL
+                    )";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('profiles')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn received_at(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'received_at'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING received_at MIGRATION");
+        let q = "ALTER TABLE messages ADD COLUMN received_at INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "UPDATE messages SET received_at = strftime('%s', created_at) WHERE inbound";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('received_at')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn outbox(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'outbox'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING outbox MIGRATION");
+        let q = "ALTER TABLE messages ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD COLUMN next_attempt_at INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD COLUMN last_error TEXT";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE messages ADD COLUMN queued_at INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "UPDATE messages SET attempts = 1, queued_at = time WHERE NOT inbound";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX messages_next_attempt_at_idx ON messages(next_attempt_at)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('outbox')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn disappearing(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'disappearing'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING disappearing MIGRATION");
+        let q = "ALTER TABLE messages ADD COLUMN expires_at INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE user_settings ADD COLUMN ttl INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX messages_expires_at_idx ON messages(expires_at)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('disappearing')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
+
+pub fn group_outbox(conn: &rusqlite::Transaction) -> Result<(), Error> {
+    let q = "SELECT * FROM migrations WHERE name = 'group_outbox'";
+    if conn
+        .query_row(q, params![], |_| Ok(()))
+        .optional()
+        .with_context(|e| format!("{}: {}", q, e))?
+        .is_none()
+    {
+        println!("EXECUTING group_outbox MIGRATION");
+        let q = "ALTER TABLE deliveries ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE deliveries ADD COLUMN next_attempt_at INTEGER";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "ALTER TABLE deliveries ADD COLUMN last_error TEXT";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "UPDATE deliveries SET attempts = 1";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "CREATE INDEX deliveries_next_attempt_at_idx ON deliveries(next_attempt_at)";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+        let q = "INSERT INTO migrations (name) VALUES ('group_outbox')";
+        conn.execute(q, params![])
+            .with_context(|e| format!("{}: {}", q, e))?;
+    }
+    Ok(())
+}
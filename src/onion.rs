@@ -0,0 +1,189 @@
+use ed25519_dalek::PublicKey;
+use failure::Fail;
+use sha3::{Digest, Sha3_256};
+
+const VERSION: u8 = 3;
+// base32 of the pubkey alone, and of `pubkey || checksum || version`
+const PUBKEY_LEN: usize = 52;
+const ADDRESS_LEN: usize = 56;
+
+/// Why a pubkey or onion address given to the API was rejected; answered with a 400.
+#[derive(Debug)]
+pub enum InvalidAddress {
+    Length,
+    Encoding,
+    Version(u8),
+    Checksum,
+    Key,
+}
+
+impl std::fmt::Display for InvalidAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidAddress::Length => write!(f, "Invalid address length"),
+            InvalidAddress::Encoding => write!(f, "Invalid base32 in address"),
+            InvalidAddress::Version(version) => write!(f, "Unsupported onion version {}", version),
+            InvalidAddress::Checksum => {
+                write!(f, "Invalid onion checksum, check the address for typos")
+            }
+            InvalidAddress::Key => write!(f, "Invalid public key"),
+        }
+    }
+}
+
+impl Fail for InvalidAddress {}
+
+fn checksum(pubkey: &[u8]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([VERSION]);
+    let mut res = [0; 2];
+    res.clone_from_slice(&hasher.finalize()[..2]);
+    res
+}
+
+/// The v3 onion address of `pubkey`, `xxxx.onion`.
+pub fn encode(pubkey: &PublicKey) -> String {
+    let mut onion = Vec::with_capacity(35);
+    onion.extend_from_slice(pubkey.as_bytes());
+    onion.extend_from_slice(&checksum(pubkey.as_bytes()));
+    onion.push(VERSION);
+    format!(
+        "{}.onion",
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &onion).to_lowercase()
+    )
+}
+
+/// Parses a v3 onion address, with or without the `.onion` suffix.
+pub fn decode(address: &str) -> Result<PublicKey, InvalidAddress> {
+    let address = address.to_uppercase();
+    let address = address.strip_suffix(".ONION").unwrap_or(&address);
+    if address.len() != ADDRESS_LEN {
+        return Err(InvalidAddress::Length);
+    }
+    let onion = base32::decode(base32::Alphabet::RFC4648 { padding: false }, address)
+        .ok_or(InvalidAddress::Encoding)?;
+    if onion[34] != VERSION {
+        return Err(InvalidAddress::Version(onion[34]));
+    }
+    if onion[32..34] != checksum(&onion[..32]) {
+        return Err(InvalidAddress::Checksum);
+    }
+    PublicKey::from_bytes(&onion[..32]).map_err(|_| InvalidAddress::Key)
+}
+
+/// Accepts either a base32 encoded pubkey or an onion address.
+pub fn parse_pubkey(s: &str) -> Result<PublicKey, InvalidAddress> {
+    if s.len() == PUBKEY_LEN {
+        PublicKey::from_bytes(
+            &base32::decode(
+                base32::Alphabet::RFC4648 { padding: false },
+                &s.to_uppercase(),
+            )
+            .ok_or(InvalidAddress::Encoding)?,
+        )
+        .map_err(|_| InvalidAddress::Key)
+    } else {
+        decode(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    const PUBKEY: &str = "DUCKDUCKGOGG42XJOC72X3SJASOWOARFBGCMVFIMAFTT6TWAGSWQ";
+
+    fn address(pubkey: &[u8], checksum: [u8; 2], version: u8) -> String {
+        let mut onion = pubkey.to_vec();
+        onion.extend_from_slice(&checksum);
+        onion.push(version);
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &onion).to_lowercase()
+    }
+
+    fn pubkey() -> PublicKey {
+        PublicKey::from_bytes(
+            &base32::decode(base32::Alphabet::RFC4648 { padding: false }, PUBKEY).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn known_address() {
+        assert_eq!(encode(&pubkey()), ADDRESS);
+        assert_eq!(decode(ADDRESS).unwrap(), pubkey());
+        assert_eq!(parse_pubkey(ADDRESS).unwrap(), pubkey());
+        assert_eq!(parse_pubkey(PUBKEY).unwrap(), pubkey());
+        assert_eq!(parse_pubkey(&PUBKEY.to_lowercase()).unwrap(), pubkey());
+    }
+
+    #[test]
+    fn suffix_and_case_are_optional() {
+        let bare = ADDRESS.strip_suffix(".onion").unwrap();
+        for address in &[
+            bare.to_owned(),
+            ADDRESS.to_uppercase(),
+            bare.to_uppercase(),
+            format!("{}.ONION", bare),
+        ] {
+            assert_eq!(decode(address).unwrap(), pubkey());
+            assert_eq!(parse_pubkey(address).unwrap(), pubkey());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let key = pubkey();
+        let mut sum = checksum(key.as_bytes());
+        sum[0] ^= 1;
+        let bad = address(key.as_bytes(), sum, VERSION);
+        assert!(matches!(decode(&bad), Err(InvalidAddress::Checksum)));
+        // a typo in the key itself is caught by the checksum too
+        let mut typo = ADDRESS.to_owned();
+        typo.replace_range(..1, "e");
+        assert!(matches!(parse_pubkey(&typo), Err(InvalidAddress::Checksum)));
+    }
+
+    #[test]
+    fn rejects_wrong_version() {
+        let key = pubkey();
+        let v2 = address(key.as_bytes(), checksum(key.as_bytes()), 2);
+        assert!(matches!(decode(&v2), Err(InvalidAddress::Version(2))));
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let bare = ADDRESS.strip_suffix(".onion").unwrap();
+        for address in &[
+            "",
+            ".onion",
+            &bare[1..],
+            &format!("{}a", bare),
+            &format!("{}a.onion", bare),
+            &PUBKEY[1..],
+            "facebookcorewwwi.onion",
+        ] {
+            assert!(matches!(parse_pubkey(address), Err(InvalidAddress::Length)));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_encoding_and_keys() {
+        let mut bad = ADDRESS.to_owned();
+        bad.replace_range(..1, "1");
+        assert!(matches!(decode(&bad), Err(InvalidAddress::Encoding)));
+        assert!(matches!(
+            parse_pubkey(&format!("1{}", &PUBKEY[1..])),
+            Err(InvalidAddress::Encoding)
+        ));
+        // not a point on the curve, even with a valid checksum
+        let key = (0..=255u8)
+            .map(|b| [b; 32])
+            .find(|key| PublicKey::from_bytes(key).is_err())
+            .unwrap();
+        let off_curve = address(&key, checksum(&key), VERSION);
+        assert!(matches!(decode(&off_curve), Err(InvalidAddress::Key)));
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::PublicKey;
+use parking_lot::Mutex;
+
+// signals further than this from our clock are dropped instead of being replay-checked
+pub const WINDOW: i64 = 60;
+pub const TYPING_TIMEOUT: i64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Typing = 0,
+    Online = 1,
+}
+
+impl Signal {
+    pub fn from_u8(signal: u8) -> Option<Self> {
+        match signal {
+            0 => Some(Signal::Typing),
+            1 => Some(Signal::Online),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Presence {
+    pub typing_until: Option<i64>,
+    pub last_online: Option<i64>,
+}
+
+impl Presence {
+    pub fn typing(&self, now: i64) -> bool {
+        self.typing_until.map(|t| t > now).unwrap_or(false)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PRESENCE: Mutex<HashMap<[u8; 32], Presence>> = Mutex::new(HashMap::new());
+}
+
+pub fn update(from: &PublicKey, signal: Signal, now: i64) {
+    let mut presence = PRESENCE.lock();
+    let entry = presence.entry(*from.as_bytes()).or_default();
+    // anything a peer sends us means it is online
+    entry.last_online = Some(now);
+    match signal {
+        Signal::Typing => entry.typing_until = Some(now + TYPING_TIMEOUT),
+        Signal::Online => (),
+    }
+}
+
+// a message from a peer ends its typing indicator
+pub fn clear_typing(from: &PublicKey, now: i64) {
+    if let Some(entry) = PRESENCE.lock().get_mut(from.as_bytes()) {
+        entry.typing_until = None;
+        entry.last_online = Some(now);
+    }
+}
+
+pub fn get(pubkey: &PublicKey) -> Presence {
+    PRESENCE
+        .lock()
+        .get(pubkey.as_bytes())
+        .copied()
+        .unwrap_or_default()
+}
@@ -2,6 +2,8 @@ use ed25519_dalek::PublicKey;
 use failure::Error;
 use uuid::Uuid;
 
+use crate::db::Message;
+
 const fn const_true() -> bool {
     true
 }
@@ -15,6 +17,13 @@ pub enum Query {
         #[serde(default)]
         #[serde(deserialize_with = "crate::util::deser_parse")]
         include_recent_messages: u8,
+        #[serde(default)]
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        include_groups: bool,
+        /// Orders the contacts only; messages always come in the order we stored them.
+        #[serde(default)]
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        order_by_received: bool,
     },
     Login,
     #[serde(rename_all = "camelCase")]
@@ -31,6 +40,35 @@ pub enum Query {
         #[serde(deserialize_with = "crate::util::deser_parse_opt")]
         limit: Option<usize>,
     },
+    Attachment {
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        id: i64,
+    },
+    Edits {
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        id: i64,
+    },
+    #[serde(rename_all = "camelCase")]
+    Group {
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        id: Uuid,
+        #[serde(flatten)]
+        limits: Limits,
+        #[serde(default = "const_true")]
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        mark_as_read: bool,
+    },
+    Deliveries {
+        #[serde(deserialize_with = "crate::util::deser_parse")]
+        id: i64,
+    },
+    /// Comma separated, to look up several messages at once.
+    #[serde(rename_all = "camelCase")]
+    Status {
+        tracking_id: String,
+    },
+    /// Messages waiting for their `send_at`, cancellable until then.
+    Scheduled,
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -52,47 +90,87 @@ pub async fn handle(q: Query) -> Result<Vec<u8>, Error> {
     match q {
         Query::Users {
             include_recent_messages,
-        } => get_user_info(include_recent_messages).await,
+            include_groups,
+            order_by_received,
+        } => get_user_info(include_recent_messages, include_groups, order_by_received).await,
         Query::Login => Ok(Vec::new()),
         Query::Messages {
             pubkey,
             limits,
             mark_as_read,
-        } => get_messages(
-            PublicKey::from_bytes(
-                &base32::decode(base32::Alphabet::RFC4648 { padding: false }, &pubkey)
-                    .ok_or_else(|| failure::format_err!("invalid pubkey"))?,
-            )?,
+        } => get_messages(crate::onion::parse_pubkey(&pubkey)?, limits, mark_as_read)
+            .await
+            .map(|(_, a)| a),
+        Query::New { pubkey, limit } => get_new(crate::onion::parse_pubkey(&pubkey)?, limit).await,
+        Query::Attachment { id } => get_attachment(id).await,
+        Query::Edits { id } => get_edits(id).await,
+        Query::Group {
+            id,
             limits,
             mark_as_read,
-        )
-        .await
-        .map(|(_, a)| a),
-        Query::New { pubkey, limit } => {
-            get_new(
-                PublicKey::from_bytes(
-                    &base32::decode(base32::Alphabet::RFC4648 { padding: false }, &pubkey)
-                        .ok_or_else(|| failure::format_err!("invalid pubkey"))?,
-                )?,
-                limit,
+        } => get_group_messages(id, limits, mark_as_read)
+            .await
+            .map(|(_, a)| a),
+        Query::Deliveries { id } => get_deliveries(id).await,
+        Query::Scheduled => get_scheduled().await,
+        Query::Status { tracking_id } => {
+            get_status(
+                tracking_id
+                    .split(',')
+                    .map(|id| id.parse())
+                    .collect::<Result<_, _>>()?,
             )
             .await
         }
     }
 }
 
-pub async fn get_user_info(include_recent_messages: u8) -> Result<Vec<u8>, Error> {
-    let dbinfo = crate::db::get_user_info().await?;
+// with `include_groups`, every record is prefixed by its type: 0 for a contact, 1 for a group
+pub async fn get_user_info(
+    include_recent_messages: u8,
+    include_groups: bool,
+    order_by_received: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut dbinfo = crate::db::get_user_info().await?;
+    if order_by_received {
+        // most recently active first, by our clock rather than the peers'
+        dbinfo.sort_by_key(|info| std::cmp::Reverse(info.last_received));
+    }
+    let now = crate::util::now();
     let mut res = Vec::new();
     for info in dbinfo {
+        if include_groups {
+            res.push(0);
+        }
         res.extend_from_slice(info.pubkey.as_bytes());
         res.extend_from_slice(&u64::to_be_bytes(info.unreads as u64));
         if let Some(name) = info.name {
-            res.push(name.as_bytes().len() as u8);
+            res.push(name.len() as u8);
             res.extend_from_slice(name.as_bytes());
         } else {
             res.push(0);
         }
+        let presence = crate::presence::get(&info.pubkey);
+        res.push(presence.typing(now) as u8);
+        res.extend_from_slice(&i64::to_be_bytes(presence.last_online.unwrap_or(0)));
+        // the contact's own profile, with an empty name if they never sent one
+        match info.profile {
+            Some(profile) => {
+                res.push(profile.name.len() as u8);
+                res.extend_from_slice(profile.name.as_bytes());
+                let bio = profile.bio.unwrap_or_default();
+                res.push(bio.len() as u8);
+                res.extend_from_slice(bio.as_bytes());
+                match profile.avatar {
+                    Some(avatar) => {
+                        res.push(1);
+                        res.extend_from_slice(&avatar);
+                    }
+                    None => res.push(0),
+                }
+            }
+            None => res.extend_from_slice(&[0, 0, 0]),
+        }
         if include_recent_messages > 0 {
             println!("including {} recent messages", include_recent_messages);
             let (count, messages) = get_messages(
@@ -108,6 +186,33 @@ pub async fn get_user_info(include_recent_messages: u8) -> Result<Vec<u8>, Error
             res.extend(messages);
         }
     }
+    if include_groups {
+        for info in crate::db::get_groups().await? {
+            res.push(1);
+            res.extend_from_slice(info.group.id.as_bytes());
+            res.extend_from_slice(&u64::to_be_bytes(info.unreads as u64));
+            res.push(info.group.name.len() as u8);
+            res.extend_from_slice(info.group.name.as_bytes());
+            res.push(info.group.active as u8);
+            res.push(info.group.members.len() as u8);
+            for member in &info.group.members {
+                res.extend_from_slice(member.as_bytes());
+            }
+            if include_recent_messages > 0 {
+                let (count, messages) = get_group_messages(
+                    info.group.id,
+                    Limits {
+                        before_after: None,
+                        limit: Some(include_recent_messages as usize),
+                    },
+                    false,
+                )
+                .await?;
+                res.push(count as u8);
+                res.extend(messages);
+            }
+        }
+    }
     Ok(res)
 }
 
@@ -116,38 +221,131 @@ pub async fn get_messages(
     limits: Limits,
     mark_as_read: bool,
 ) -> Result<(usize, Vec<u8>), Error> {
-    let dbmsgs = crate::db::get_messages(pubkey, limits, mark_as_read).await?;
+    let (dbmsgs, read) = crate::db::get_messages(pubkey, limits, mark_as_read).await?;
+    crate::message::send_read_receipts(pubkey, read);
     let count = dbmsgs.len();
     let mut res = Vec::new();
     for msg in dbmsgs {
-        if msg.inbound {
-            res.push(1);
-        } else {
-            res.push(0);
-        }
-        res.extend_from_slice(&i64::to_be_bytes(msg.id));
-        res.extend_from_slice(&msg.tracking_id.unwrap_or_else(Uuid::nil).as_bytes()[..]);
-        res.extend_from_slice(&i64::to_be_bytes(msg.time));
-        res.extend_from_slice(&u64::to_be_bytes(msg.content.as_bytes().len() as u64));
-        res.extend_from_slice(msg.content.as_bytes());
+        write_message(&mut res, &msg);
     }
     Ok((count, res))
 }
 
 pub async fn get_new(pubkey: PublicKey, limit: Option<usize>) -> Result<Vec<u8>, Error> {
-    let dbmsgs = crate::db::get_new_messages(pubkey, limit, true).await?;
+    let (dbmsgs, read) = crate::db::get_new_messages(pubkey, limit, true).await?;
+    crate::message::send_read_receipts(pubkey, read);
     let mut res = Vec::new();
     for msg in dbmsgs {
-        if msg.inbound {
-            res.push(1);
-        } else {
-            res.push(0);
-        }
-        res.extend_from_slice(&i64::to_be_bytes(msg.id));
-        res.extend_from_slice(&msg.tracking_id.unwrap_or_else(Uuid::nil).as_bytes()[..]);
-        res.extend_from_slice(&i64::to_be_bytes(msg.time));
-        res.extend_from_slice(&u64::to_be_bytes(msg.content.as_bytes().len() as u64));
-        res.extend_from_slice(msg.content.as_bytes());
+        write_message(&mut res, &msg);
     }
     Ok(res)
 }
+
+// each message is prefixed by the pubkey of its sender, ours for outbound messages
+pub async fn get_group_messages(
+    id: Uuid,
+    limits: Limits,
+    mark_as_read: bool,
+) -> Result<(usize, Vec<u8>), Error> {
+    let dbmsgs = crate::db::get_group_messages(id, limits, mark_as_read).await?;
+    let count = dbmsgs.len();
+    let mut res = Vec::new();
+    for (sender, msg) in dbmsgs {
+        res.extend_from_slice(&sender);
+        write_message(&mut res, &msg);
+    }
+    Ok((count, res))
+}
+
+pub async fn get_deliveries(id: i64) -> Result<Vec<u8>, Error> {
+    let mut res = Vec::new();
+    for (pubkey, status) in crate::db::get_deliveries(id).await? {
+        res.extend_from_slice(pubkey.as_bytes());
+        res.push(status as u8);
+    }
+    Ok(res)
+}
+
+pub async fn get_status(tracking_ids: Vec<Uuid>) -> Result<Vec<u8>, Error> {
+    let mut res = Vec::new();
+    for status in crate::db::get_send_status(tracking_ids).await? {
+        res.extend_from_slice(status.tracking_id.as_bytes());
+        res.extend_from_slice(&i64::to_be_bytes(status.id));
+        res.push(status.state as u8);
+        res.extend_from_slice(&u32::to_be_bytes(status.attempts));
+        let mut last_error = status.last_error.unwrap_or_default();
+        crate::util::truncate(&mut last_error, u16::MAX as usize);
+        res.extend_from_slice(&u16::to_be_bytes(last_error.len() as u16));
+        res.extend_from_slice(last_error.as_bytes());
+    }
+    Ok(res)
+}
+
+pub async fn get_scheduled() -> Result<Vec<u8>, Error> {
+    let mut res = Vec::new();
+    for scheduled in crate::db::get_scheduled().await? {
+        res.extend_from_slice(&i64::to_be_bytes(scheduled.id));
+        res.extend_from_slice(scheduled.tracking_id.unwrap_or_else(Uuid::nil).as_bytes());
+        res.extend_from_slice(scheduled.to.as_bytes());
+        res.extend_from_slice(&i64::to_be_bytes(scheduled.send_at));
+        res.extend_from_slice(&u64::to_be_bytes(scheduled.content.len() as u64));
+        res.extend_from_slice(scheduled.content.as_bytes());
+    }
+    Ok(res)
+}
+
+pub async fn get_attachment(id: i64) -> Result<Vec<u8>, Error> {
+    match crate::db::get_attachment(id).await? {
+        Some((inbound, complete, data)) if complete || !inbound => Ok(data),
+        Some(_) => failure::bail!("Attachment incomplete"),
+        None => failure::bail!("Attachment not found"),
+    }
+}
+
+pub async fn get_edits(id: i64) -> Result<Vec<u8>, Error> {
+    let edits = crate::db::get_edits(id).await?;
+    let mut res = Vec::new();
+    for edit in edits {
+        res.extend_from_slice(&i64::to_be_bytes(edit.time));
+        res.extend_from_slice(&u64::to_be_bytes(edit.content.len() as u64));
+        res.extend_from_slice(edit.content.as_bytes());
+    }
+    Ok(res)
+}
+
+fn write_message(res: &mut Vec<u8>, msg: &Message) {
+    if msg.inbound {
+        res.push(1);
+    } else {
+        res.push(0);
+    }
+    res.extend_from_slice(&i64::to_be_bytes(msg.id));
+    res.extend_from_slice(&msg.tracking_id.unwrap_or_else(Uuid::nil).as_bytes()[..]);
+    res.extend_from_slice(&i64::to_be_bytes(msg.time));
+    res.extend_from_slice(&u64::to_be_bytes(msg.content.len() as u64));
+    res.extend_from_slice(msg.content.as_bytes());
+    res.push(msg.status as u8);
+    res.extend_from_slice(&i64::to_be_bytes(msg.read_at.unwrap_or(0)));
+    res.extend_from_slice(&i64::to_be_bytes(msg.attachment.unwrap_or(0)));
+    res.push(msg.attachment_complete.unwrap_or(false) as u8);
+    res.extend_from_slice(msg.uuid.unwrap_or_else(Uuid::nil).as_bytes());
+    res.extend_from_slice(msg.reply_to.unwrap_or_else(Uuid::nil).as_bytes());
+    res.extend_from_slice(&i64::to_be_bytes(msg.reply_to_id.unwrap_or(0)));
+    res.push(msg.edited as u8);
+    res.push(msg.retracted as u8);
+    res.push(msg.reactions.len() as u8);
+    for reaction in &msg.reactions {
+        res.push(reaction.emoji.len() as u8);
+        res.extend_from_slice(reaction.emoji.as_bytes());
+        res.extend_from_slice(&u64::to_be_bytes(reaction.count as u64));
+        res.push(reaction.mine as u8);
+    }
+    res.push(msg.content_type);
+    res.extend_from_slice(&i64::to_be_bytes(msg.received_at.unwrap_or(0)));
+    res.push(
+        msg.received_at.is_some_and(|received_at| {
+            (msg.time - received_at).abs() > crate::CONFIG.clock_skew_window
+        }) as u8,
+    );
+    res.extend_from_slice(&i64::to_be_bytes(msg.expires_at.unwrap_or(0)));
+}
@@ -0,0 +1,491 @@
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, Signature, Verifier};
+use failure::Error;
+use sha3::{Digest, Sha3_256};
+use std::convert::TryFrom;
+
+use crate::message::{Inbound, NewOutboundMessage};
+
+// message keys held back for messages that arrive out of order
+pub const MAX_SKIP: u32 = 1000;
+// our signed prekey is replaced after this long, and forgotten after twice this long
+pub const PREKEY_LIFETIME: i64 = 60 * 60 * 24 * 7;
+
+lazy_static::lazy_static! {
+    // ratchet steps are a read-modify-write of the stored session
+    static ref LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+fn eof() -> std::io::Error {
+    std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
+}
+
+/// The X3DH header an initiator repeats until its peer first answers.
+#[derive(Clone, Debug)]
+pub struct Init {
+    pub identity: PublicKey,
+    pub ephemeral: MontgomeryPoint,
+    pub prekey: MontgomeryPoint,
+}
+
+#[derive(Clone)]
+pub struct Session {
+    pub id: [u8; 16],
+    pub peer: PublicKey,
+    pub init: Option<Init>,
+    root: [u8; 32],
+    dh_secret: Scalar,
+    dh_remote: Option<MontgomeryPoint>,
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    send_n: u32,
+    recv_n: u32,
+    prev_n: u32,
+    skipped: Vec<([u8; 32], u32, [u8; 32])>,
+}
+
+fn x3dh(
+    dh1: MontgomeryPoint,
+    dh2: MontgomeryPoint,
+    dh3: MontgomeryPoint,
+    initiator: &PublicKey,
+    responder: &PublicKey,
+) -> Result<[u8; 32], Error> {
+    if [dh1, dh2, dh3].iter().any(|dh| dh.as_bytes() == &[0; 32]) {
+        failure::bail!("invalid session key");
+    }
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"cups x3dh");
+    hasher.update(dh1.as_bytes());
+    hasher.update(dh2.as_bytes());
+    hasher.update(dh3.as_bytes());
+    hasher.update(initiator.as_bytes());
+    hasher.update(responder.as_bytes());
+    let mut res = [0; 32];
+    res.clone_from_slice(&hasher.finalize());
+    Ok(res)
+}
+
+fn kdf(label: &[u8], key: &[u8; 32], input: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(label);
+    hasher.update(key);
+    hasher.update(input);
+    let mut res = [0; 32];
+    res.clone_from_slice(&hasher.finalize());
+    res
+}
+
+// returns the next root key and the new chain key
+fn kdf_root(root: &[u8; 32], dh: &MontgomeryPoint) -> ([u8; 32], [u8; 32]) {
+    (
+        kdf(b"cups ratchet root", root, dh.as_bytes()),
+        kdf(b"cups ratchet chain", root, dh.as_bytes()),
+    )
+}
+
+// returns the next chain key and the message key
+fn kdf_chain(chain: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (
+        kdf(b"cups chain key", chain, &[]),
+        kdf(b"cups message key", chain, &[]),
+    )
+}
+
+// every message key is used exactly once, so a fixed nonce never repeats for a key
+fn seal(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt(
+            &Nonce::from([0; 12]),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| failure::format_err!("encryption failed"))
+}
+
+fn open(key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .decrypt(
+            &Nonce::from([0; 12]),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| failure::format_err!("Session out of sync"))
+}
+
+impl Session {
+    pub fn initiate(
+        key: &ExpandedSecretKey,
+        peer: PublicKey,
+        prekey: MontgomeryPoint,
+    ) -> Result<Self, Error> {
+        let identity = crate::wire::x25519_scalar(key);
+        let ephemeral = Scalar::random(&mut rand::rngs::OsRng);
+        let shared = x3dh(
+            prekey * identity,
+            crate::wire::x25519_pubkey(&peer)? * ephemeral,
+            prekey * ephemeral,
+            &PublicKey::from(key),
+            &peer,
+        )?;
+        let dh_secret = Scalar::random(&mut rand::rngs::OsRng);
+        let (root, send_chain) = kdf_root(&shared, &(prekey * dh_secret));
+        Ok(Session {
+            id: rand::random(),
+            peer,
+            init: Some(Init {
+                identity: PublicKey::from(key),
+                ephemeral: X25519_BASEPOINT * ephemeral,
+                prekey,
+            }),
+            root,
+            dh_secret,
+            dh_remote: Some(prekey),
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_n: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    // the responder's first ratchet key is its signed prekey
+    pub fn respond(
+        key: &ExpandedSecretKey,
+        id: [u8; 16],
+        init: &Init,
+        prekey: Scalar,
+    ) -> Result<Self, Error> {
+        let identity = crate::wire::x25519_scalar(key);
+        let shared = x3dh(
+            crate::wire::x25519_pubkey(&init.identity)? * prekey,
+            init.ephemeral * identity,
+            init.ephemeral * prekey,
+            &init.identity,
+            &PublicKey::from(key),
+        )?;
+        Ok(Session {
+            id,
+            peer: init.identity,
+            init: None,
+            root: shared,
+            dh_secret: prekey,
+            dh_remote: None,
+            send_chain: None,
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_n: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Encrypts the next message, returning `ratchet pubkey || previous chain length || n || ciphertext`.
+    /// `aad` binds whatever framing precedes the header.
+    pub fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.send_chain.is_none() {
+            self.step_send()?;
+        }
+        let (chain, message_key) = kdf_chain(self.send_chain.as_ref().ok_or_else(eof)?);
+        let mut res = Vec::with_capacity(40 + plaintext.len() + 16);
+        res.extend_from_slice((X25519_BASEPOINT * self.dh_secret).as_bytes());
+        res.extend_from_slice(&u32::to_be_bytes(self.prev_n));
+        res.extend_from_slice(&u32::to_be_bytes(self.send_n));
+        let mut full_aad = aad.to_vec();
+        full_aad.extend_from_slice(&res);
+        res.extend(seal(&message_key, &full_aad, plaintext)?);
+        self.send_chain = Some(chain);
+        self.send_n += 1;
+        Ok(res)
+    }
+
+    /// Decrypts a message produced by `encrypt`. The session is left untouched on failure.
+    pub fn decrypt(&mut self, aad: &[u8], bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut dh = [0; 32];
+        dh.clone_from_slice(bytes.get(..32).ok_or_else(eof)?);
+        let mut prev_n = [0; 4];
+        prev_n.clone_from_slice(bytes.get(32..36).ok_or_else(eof)?);
+        let mut n = [0; 4];
+        n.clone_from_slice(bytes.get(36..40).ok_or_else(eof)?);
+        let (prev_n, n) = (u32::from_be_bytes(prev_n), u32::from_be_bytes(n));
+        let mut full_aad = aad.to_vec();
+        full_aad.extend_from_slice(&bytes[..40]);
+        let ciphertext = &bytes[40..];
+
+        let mut next = self.clone();
+        if let Some(idx) = next
+            .skipped
+            .iter()
+            .position(|(skipped_dh, skipped_n, _)| skipped_dh == &dh && *skipped_n == n)
+        {
+            let (_, _, message_key) = next.skipped.remove(idx);
+            let res = open(&message_key, &full_aad, ciphertext)?;
+            *self = next;
+            return Ok(res);
+        }
+        let dh = MontgomeryPoint(dh);
+        if next.dh_remote != Some(dh) {
+            next.skip_until(prev_n)?;
+            next.dh_remote = Some(dh);
+            let (root, recv_chain) = kdf_root(&next.root, &(dh * next.dh_secret));
+            next.root = root;
+            next.recv_chain = Some(recv_chain);
+            next.recv_n = 0;
+            next.prev_n = next.send_n;
+            next.send_n = 0;
+            next.send_chain = None;
+        }
+        next.skip_until(n)?;
+        let (chain, message_key) = kdf_chain(
+            next.recv_chain
+                .as_ref()
+                .ok_or_else(|| failure::format_err!("Session out of sync"))?,
+        );
+        let res = open(&message_key, &full_aad, ciphertext)?;
+        next.recv_chain = Some(chain);
+        next.recv_n += 1;
+        // the peer has answered, so it no longer needs our handshake
+        next.init = None;
+        *self = next;
+        Ok(res)
+    }
+
+    fn step_send(&mut self) -> Result<(), Error> {
+        let remote = self
+            .dh_remote
+            .ok_or_else(|| failure::format_err!("Session has no peer ratchet key"))?;
+        self.dh_secret = Scalar::random(&mut rand::rngs::OsRng);
+        let (root, send_chain) = kdf_root(&self.root, &(remote * self.dh_secret));
+        self.root = root;
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+
+    fn skip_until(&mut self, n: u32) -> Result<(), Error> {
+        let (mut chain, dh) = match (self.recv_chain, self.dh_remote) {
+            (Some(chain), Some(dh)) => (chain, dh),
+            _ => return Ok(()),
+        };
+        if n.saturating_sub(self.recv_n) > MAX_SKIP {
+            failure::bail!("Too many skipped messages");
+        }
+        while self.recv_n < n {
+            let (next, message_key) = kdf_chain(&chain);
+            self.skipped
+                .push((*dh.as_bytes(), self.recv_n, message_key));
+            chain = next;
+            self.recv_n += 1;
+        }
+        self.recv_chain = Some(chain);
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        fn push_opt(res: &mut Vec<u8>, bytes: Option<&[u8; 32]>) {
+            match bytes {
+                Some(bytes) => {
+                    res.push(1);
+                    res.extend_from_slice(bytes);
+                }
+                None => res.push(0),
+            }
+        }
+
+        let mut res = Vec::new();
+        match &self.init {
+            Some(init) => {
+                res.push(1);
+                res.extend_from_slice(init.ephemeral.as_bytes());
+                res.extend_from_slice(init.prekey.as_bytes());
+            }
+            None => res.push(0),
+        }
+        res.extend_from_slice(&self.root);
+        res.extend_from_slice(self.dh_secret.as_bytes());
+        push_opt(&mut res, self.dh_remote.as_ref().map(|dh| dh.as_bytes()));
+        push_opt(&mut res, self.send_chain.as_ref());
+        push_opt(&mut res, self.recv_chain.as_ref());
+        res.extend_from_slice(&u32::to_be_bytes(self.send_n));
+        res.extend_from_slice(&u32::to_be_bytes(self.recv_n));
+        res.extend_from_slice(&u32::to_be_bytes(self.prev_n));
+        for (dh, n, message_key) in &self.skipped {
+            res.extend_from_slice(dh);
+            res.extend_from_slice(&u32::to_be_bytes(*n));
+            res.extend_from_slice(message_key);
+        }
+        res
+    }
+
+    pub fn parse(
+        id: [u8; 16],
+        peer: PublicKey,
+        local: &PublicKey,
+        bytes: &[u8],
+    ) -> Result<Self, Error> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+            if bytes.len() < len {
+                return Err(eof().into());
+            }
+            let (res, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Ok(res)
+        }
+        fn take_32(bytes: &mut &[u8]) -> Result<[u8; 32], Error> {
+            let mut res = [0; 32];
+            res.clone_from_slice(take(bytes, 32)?);
+            Ok(res)
+        }
+        fn take_opt(bytes: &mut &[u8]) -> Result<Option<[u8; 32]>, Error> {
+            Ok(match take(bytes, 1)?[0] {
+                0 => None,
+                _ => Some(take_32(bytes)?),
+            })
+        }
+        fn take_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+            let mut res = [0; 4];
+            res.clone_from_slice(take(bytes, 4)?);
+            Ok(u32::from_be_bytes(res))
+        }
+
+        let mut bytes = bytes;
+        let init = match take(&mut bytes, 1)?[0] {
+            0 => None,
+            _ => Some(Init {
+                identity: *local,
+                ephemeral: MontgomeryPoint(take_32(&mut bytes)?),
+                prekey: MontgomeryPoint(take_32(&mut bytes)?),
+            }),
+        };
+        let root = take_32(&mut bytes)?;
+        let dh_secret = Scalar::from_bits(take_32(&mut bytes)?);
+        let dh_remote = take_opt(&mut bytes)?.map(MontgomeryPoint);
+        let send_chain = take_opt(&mut bytes)?;
+        let recv_chain = take_opt(&mut bytes)?;
+        let send_n = take_u32(&mut bytes)?;
+        let recv_n = take_u32(&mut bytes)?;
+        let prev_n = take_u32(&mut bytes)?;
+        let mut skipped = Vec::with_capacity(bytes.len() / 68);
+        while !bytes.is_empty() {
+            let dh = take_32(&mut bytes)?;
+            let n = take_u32(&mut bytes)?;
+            skipped.push((dh, n, take_32(&mut bytes)?));
+        }
+        Ok(Session {
+            id,
+            peer,
+            init,
+            root,
+            dh_secret,
+            dh_remote,
+            send_chain,
+            recv_chain,
+            send_n,
+            recv_n,
+            prev_n,
+            skipped,
+        })
+    }
+}
+
+/// Our signed prekey, as served to peers starting a session: `x25519 prekey || sig`.
+pub async fn prekey_bundle() -> Result<Vec<u8>, Error> {
+    let now = crate::util::now();
+    let secret = match crate::db::get_current_prekey(now - PREKEY_LIFETIME).await? {
+        Some(secret) => secret,
+        None => {
+            let secret = Scalar::random(&mut rand::rngs::OsRng);
+            crate::db::save_prekey(secret, now, now - 2 * PREKEY_LIFETIME).await?;
+            secret
+        }
+    };
+    let prekey = X25519_BASEPOINT * secret;
+    let mut res = Vec::with_capacity(96);
+    res.extend_from_slice(prekey.as_bytes());
+    res.extend_from_slice(
+        &crate::SECKEY
+            .sign(&prekey_payload(&prekey), &PublicKey::from(&*crate::SECKEY))
+            .to_bytes(),
+    );
+    Ok(res)
+}
+
+pub fn verify_prekey_bundle(signer: &PublicKey, bytes: &[u8]) -> Result<MontgomeryPoint, Error> {
+    let mut prekey = [0; 32];
+    prekey.clone_from_slice(bytes.get(..32).ok_or_else(eof)?);
+    let prekey = MontgomeryPoint(prekey);
+    let sig = Signature::try_from(bytes.get(32..96).ok_or_else(eof)?)?;
+    signer.verify(&prekey_payload(&prekey), &sig)?;
+    Ok(prekey)
+}
+
+fn prekey_payload(prekey: &MontgomeryPoint) -> Vec<u8> {
+    let mut res = Vec::with_capacity(43);
+    res.extend_from_slice(b"cups prekey");
+    res.extend_from_slice(prekey.as_bytes());
+    res
+}
+
+// a new session needs the peer's prekey, which is fetched over Tor before taking the lock so that
+// a slow peer holds up only its own messages
+pub async fn encode(msg: &NewOutboundMessage, max_len: u64) -> Result<Vec<u8>, Error> {
+    let mut prekey = None;
+    loop {
+        if prekey.is_none() && crate::db::get_session(msg.to).await?.is_none() {
+            prekey = Some(crate::message::prekey(&msg.to).await?);
+        }
+        let _lock = LOCK.lock().await;
+        let mut session = match (crate::db::get_session(msg.to).await?, prekey) {
+            (Some(session), _) => session,
+            (None, Some(prekey)) => Session::initiate(&crate::SECKEY, msg.to, prekey)?,
+            // dropped while we waited for the lock
+            (None, None) => continue,
+        };
+        let res = crate::wire::encode(
+            &crate::SECKEY,
+            2,
+            msg,
+            Some(&mut session),
+            crate::CONFIG.padding,
+            max_len,
+        )?;
+        crate::db::save_session(session, crate::util::now()).await?;
+        return Ok(res);
+    }
+}
+
+pub async fn parse(bytes: &[u8]) -> Result<Inbound, Error> {
+    let (id, init) = match crate::wire::parse_session(&crate::SECKEY, bytes)? {
+        Some(header) => header,
+        None => return crate::wire::parse(&crate::SECKEY, bytes, None),
+    };
+    let _lock = LOCK.lock().await;
+    let mut session = match crate::db::get_session_by_id(id).await? {
+        Some(session) => session,
+        None => {
+            let init = init.ok_or_else(|| failure::format_err!("Unknown session"))?;
+            let prekey = crate::db::get_prekey(init.prekey)
+                .await?
+                .ok_or_else(|| failure::format_err!("Unknown session"))?;
+            Session::respond(&crate::SECKEY, id, &init, prekey)?
+        }
+    };
+    let res = crate::wire::parse(&crate::SECKEY, bytes, Some(&mut session))?;
+    crate::db::save_session(session, crate::util::now()).await?;
+    Ok(res)
+}
+
+// a peer that lost our session or prekey can only be reached again through a new handshake
+pub fn is_stale(err: &str) -> bool {
+    err.contains("Unknown session") || err.contains("Session out of sync")
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::PublicKey;
+use failure::Error;
+use futures::future::BoxFuture;
+use hyper::{Body, Request, StatusCode};
+
+/// How we reach peers, selected by `transport` in the config.
+pub trait Transport: Send + Sync {
+    /// POSTs `body` to the peer, answering with its status and body.
+    fn post<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>>;
+
+    /// GETs the peer's version, or with a `query` one of its unauthenticated endpoints.
+    fn get<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        query: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>>;
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportConfig {
+    /// Onion services through the SOCKS proxy.
+    #[default]
+    Tor,
+    /// Plain HTTP to a fixed address per peer, keyed by base32 pubkey or onion address, for LANs.
+    Direct { peers: HashMap<String, String> },
+    /// This server is its only peer, handled in-process; only useful for testing. Sessions are
+    /// not offered over it since both ends of one would share a database.
+    Loopback,
+}
+
+lazy_static::lazy_static! {
+    pub static ref TRANSPORT: Box<dyn Transport> = match &crate::CONFIG.transport {
+        TransportConfig::Tor => Box::new(Tor(
+            reqwest::Client::builder().proxy(crate::PROXY.clone()).build().expect("CLIENT"),
+        )),
+        TransportConfig::Direct { peers } => Box::new(Direct {
+            client: reqwest::Client::builder().no_proxy().build().expect("CLIENT"),
+            peers: peers
+                .iter()
+                .map(|(pubkey, addr)| {
+                    Ok((
+                        crate::onion::parse_pubkey(pubkey)?.as_bytes().to_vec(),
+                        addr.clone(),
+                    ))
+                })
+                .collect::<Result<_, Error>>()
+                .expect("transport.peers"),
+        }),
+        TransportConfig::Loopback => Box::new(Loopback),
+    };
+}
+
+pub fn is_loopback() -> bool {
+    matches!(crate::CONFIG.transport, TransportConfig::Loopback)
+}
+
+pub fn url(pubkey: &PublicKey) -> String {
+    format!(
+        "http://{}:{}",
+        crate::onion::encode(pubkey),
+        crate::CONFIG.peer_port
+    )
+}
+
+fn with_query(url: String, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("{}/?{}", url, query),
+        None => url,
+    }
+}
+
+async fn fetch(req: reqwest::RequestBuilder) -> Result<(StatusCode, Vec<u8>), Error> {
+    let res = req.send().await?;
+    Ok((res.status(), res.bytes().await?.to_vec()))
+}
+
+pub struct Tor(reqwest::Client);
+
+impl Transport for Tor {
+    fn post<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>> {
+        Box::pin(fetch(self.0.post(url(to)).body(body)))
+    }
+
+    fn get<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        query: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>> {
+        Box::pin(fetch(self.0.get(with_query(url(to), query))))
+    }
+}
+
+pub struct Direct {
+    client: reqwest::Client,
+    peers: HashMap<Vec<u8>, String>,
+}
+
+impl Direct {
+    fn url(&self, pubkey: &PublicKey) -> Result<String, Error> {
+        self.peers
+            .get(&pubkey.as_bytes()[..])
+            .map(|addr| format!("http://{}", addr))
+            .ok_or_else(|| failure::format_err!("No address for peer"))
+    }
+}
+
+impl Transport for Direct {
+    fn post<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>> {
+        Box::pin(async move { fetch(self.client.post(self.url(to)?).body(body)).await })
+    }
+
+    fn get<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        query: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>> {
+        Box::pin(async move { fetch(self.client.get(with_query(self.url(to)?, query))).await })
+    }
+}
+
+pub struct Loopback;
+
+impl Loopback {
+    // anyone else's messages would be refused as addressed to another recipient
+    fn check(to: &PublicKey) -> Result<(), Error> {
+        if to != &PublicKey::from(&*crate::SECKEY) {
+            failure::bail!("Loopback only reaches this server");
+        }
+        Ok(())
+    }
+
+    async fn handle(req: Request<Body>) -> Result<(StatusCode, Vec<u8>), Error> {
+        let res = crate::handle(req).await?;
+        let status = res.status();
+        Ok((
+            status,
+            hyper::body::to_bytes(res.into_body()).await?.to_vec(),
+        ))
+    }
+}
+
+impl Transport for Loopback {
+    fn post<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>> {
+        Box::pin(async move {
+            Loopback::check(to)?;
+            Loopback::handle(
+                Request::post("/")
+                    .header("Content-Length", body.len())
+                    .body(Body::from(body))?,
+            )
+            .await
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        to: &'a PublicKey,
+        query: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(StatusCode, Vec<u8>), Error>> {
+        Box::pin(async move {
+            Loopback::check(to)?;
+            let uri = match query {
+                Some(query) => format!("/?{}", query),
+                None => "/".to_owned(),
+            };
+            Loopback::handle(Request::get(uri).body(Body::empty())?).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MessageStatus;
+    use crate::message::{Content, NewOutboundMessage};
+
+    #[tokio::test]
+    async fn loopback_send_receive() {
+        crate::db::migrated().await;
+        let local = PublicKey::from(&*crate::SECKEY);
+        assert!(!crate::message::capabilities(&local, true)
+            .await
+            .unwrap()
+            .versions
+            .contains(&2));
+        let status = crate::message::send(NewOutboundMessage {
+            tracking_id: None,
+            to: local,
+            nonce: rand::random(),
+            uuid: uuid::Uuid::new_v4(),
+            reply_to: None,
+            group: None,
+            time: crate::util::now(),
+            ttl: None,
+            content: Content::Text("over the loopback".to_owned()),
+        })
+        .await
+        .unwrap();
+        assert_eq!(status, MessageStatus::Delivered);
+        let (messages, _) = crate::db::get_messages(
+            local,
+            crate::query::Limits {
+                limit: None,
+                before_after: None,
+            },
+            false,
+        )
+        .await
+        .unwrap();
+        // our outbound copy, and the one the loopback peer received
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().any(|m| m.inbound));
+        assert!(messages.iter().any(|m| !m.inbound));
+        assert!(messages.iter().all(|m| m.content == "over the loopback"));
+
+        let other = PublicKey::from(&ed25519_dalek::ExpandedSecretKey::from(
+            &ed25519_dalek::SecretKey::generate(&mut rand::rngs::OsRng),
+        ));
+        assert!(TRANSPORT.post(&other, Vec::new()).await.is_err());
+    }
+}
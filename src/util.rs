@@ -17,3 +17,21 @@ pub fn deser_parse_opt<'de, E: Display, T: FromStr<Err = E> + Sized, D: Deserial
     s.map(|s| s.parse().map_err(serde::de::Error::custom))
         .transpose()
 }
+
+pub fn now() -> i64 {
+    std::time::UNIX_EPOCH
+        .elapsed()
+        .map(|a| a.as_secs() as i64)
+        .unwrap_or_else(|a| -(a.duration().as_secs() as i64))
+}
+
+// cuts `s` to at most `max` bytes without splitting a character
+pub fn truncate(s: &mut String, max: usize) {
+    if s.len() > max {
+        let mut len = max;
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        s.truncate(len);
+    }
+}
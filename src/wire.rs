@@ -1,60 +1,701 @@
 use std::convert::TryFrom;
 
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
 use ed25519_dalek::{ExpandedSecretKey, PublicKey, Signature, Verifier};
 use failure::Error;
+use sha3::{Digest, Sha3_256};
+use uuid::Uuid;
 
-use crate::message::{NewInboundMessage, NewOutboundMessage};
+use crate::message::{Content, Inbound, NewInboundMessage, NewOutboundMessage};
+use crate::session::{Init, Session};
 
-pub fn parse(bytes: &[u8]) -> Result<NewInboundMessage, Error> {
-    if bytes
-        .get(0)
-        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
-        != &0
-    {
-        failure::bail!("Unsupported version");
+fn eof() -> std::io::Error {
+    std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
+}
+
+pub const VERSIONS: &[u8] = &[0, 1, 2];
+// ephemeral pubkey || initiator || ephemeral || prekey || tag
+const SEALED_INIT_LEN: usize = 32 + 96 + 16;
+// messages are never padded below this
+const MIN_PADDED_LEN: u64 = 256;
+
+/// How encrypted messages are padded to hide their length.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Padding {
+    /// Only the end marker, leaking the exact length.
+    None,
+    /// The next power of two, at most doubling the size.
+    #[default]
+    PowerOfTwo,
+    /// Padmé: leaks O(log log n) bits of the length for at most 12% overhead.
+    Padme,
+}
+
+impl Padding {
+    pub fn padded_len(self, len: u64) -> u64 {
+        match self {
+            Padding::None => len,
+            Padding::PowerOfTwo => len.max(MIN_PADDED_LEN).next_power_of_two(),
+            Padding::Padme => {
+                let len = len.max(MIN_PADDED_LEN);
+                let exp = 63 - len.leading_zeros() as u64;
+                let mask = (1 << (exp - (63 - exp.leading_zeros() as u64) - 1)) - 1;
+                (len + mask) & !mask
+            }
+        }
+    }
+}
+
+/// Wire versions:
+///
+/// - 0: `pubkey || sig || time || content`, signed but in plaintext
+/// - 1: `ephemeral x25519 pubkey || ChaCha20Poly1305(pubkey || sig || envelope)`, encrypted to the
+///   recipient's onion key, see [`crate::envelope`]
+/// - 2: `session id || has init || [v1-sealed(initiator || ephemeral x25519 pubkey || prekey)] || ratchet header || ciphertext`,
+///   the version 1 signed frame encrypted under a double ratchet session, see [`crate::session`]
+///
+/// Version 0 only carries text and no nonce, so the first 16 bytes of its signature stand in for
+/// one, and for its uuid.
+///
+/// The plaintext of versions 1 and 2 is followed by `0x80` and as many zeros as the sender's
+/// [`Padding`] calls for.
+///
+/// Version 2 messages need the session named by [`parse_session`].
+pub fn parse(
+    key: &ExpandedSecretKey,
+    bytes: &[u8],
+    session: Option<&mut Session>,
+) -> Result<Inbound, Error> {
+    match bytes.first().ok_or_else(eof)? {
+        0 => parse_v0(&bytes[1..]).map(Inbound::Message),
+        1 => parse_v1(key, unpad(&decrypt(key, &bytes[1..])?)?),
+        2 => {
+            let session = session.ok_or_else(|| failure::format_err!("Unknown session"))?;
+            let header_len = session_header_len(bytes)?;
+            let plaintext = session.decrypt(
+                bytes.get(..header_len).ok_or_else(eof)?,
+                bytes.get(header_len..).ok_or_else(eof)?,
+            )?;
+            let msg = parse_v1(key, unpad(&plaintext)?)?;
+            if msg.from() != &session.peer {
+                failure::bail!("Message signed by another sender");
+            }
+            Ok(msg)
+        }
+        _ => failure::bail!("Unsupported version"),
+    }
+}
+
+/// A version 2 session id, along with the handshake if the initiator included one.
+pub type SessionHeader = ([u8; 16], Option<Init>);
+
+pub fn parse_session(
+    key: &ExpandedSecretKey,
+    bytes: &[u8],
+) -> Result<Option<SessionHeader>, Error> {
+    if bytes.first() != Some(&2) {
+        return Ok(None);
+    }
+    let mut id = [0; 16];
+    id.clone_from_slice(bytes.get(1..17).ok_or_else(eof)?);
+    let init = match bytes.get(17).ok_or_else(eof)? {
+        0 => None,
+        _ => {
+            let init = decrypt(
+                key,
+                bytes.get(18..session_header_len(bytes)?).ok_or_else(eof)?,
+            )?;
+            let mut ephemeral = [0; 32];
+            ephemeral.clone_from_slice(init.get(32..64).ok_or_else(eof)?);
+            let mut prekey = [0; 32];
+            prekey.clone_from_slice(init.get(64..96).ok_or_else(eof)?);
+            Some(Init {
+                identity: PublicKey::from_bytes(&init[..32])?,
+                ephemeral: MontgomeryPoint(ephemeral),
+                prekey: MontgomeryPoint(prekey),
+            })
+        }
+    };
+    Ok(Some((id, init)))
+}
+
+// version || session id || has init || sealed init
+fn session_header_len(bytes: &[u8]) -> Result<usize, Error> {
+    Ok(match bytes.get(17).ok_or_else(eof)? {
+        0 => 18,
+        _ => 18 + SEALED_INIT_LEN,
+    })
+}
+
+pub fn encode(
+    key: &ExpandedSecretKey,
+    version: u8,
+    message: &NewOutboundMessage,
+    session: Option<&mut Session>,
+    padding: Padding,
+    max_len: u64,
+) -> Result<Vec<u8>, Error> {
+    let mut res = Vec::new();
+    res.push(version);
+    match version {
+        0 => {
+            let content = match &message.content {
+                Content::Text(text) => text,
+                _ => failure::bail!("Unsupported content for version 0"),
+            };
+            let mut payload = Vec::with_capacity(8 + content.len());
+            payload.extend_from_slice(&i64::to_be_bytes(message.time));
+            payload.extend_from_slice(content.as_bytes());
+            res.extend_from_slice(&sign(key, &payload));
+        }
+        1 => {
+            let mut plaintext = sign(key, &crate::envelope::encode(message));
+            // version || ephemeral pubkey || tag
+            pad(&mut plaintext, 1 + 32 + 16, padding, max_len);
+            res.extend_from_slice(&encrypt(&message.to, &plaintext)?);
+        }
+        2 => {
+            let session = session.ok_or_else(|| failure::format_err!("Unknown session"))?;
+            res.extend_from_slice(&session.id);
+            match &session.init {
+                Some(init) => {
+                    res.push(1);
+                    let mut header = Vec::with_capacity(96);
+                    header.extend_from_slice(init.identity.as_bytes());
+                    header.extend_from_slice(init.ephemeral.as_bytes());
+                    header.extend_from_slice(init.prekey.as_bytes());
+                    res.extend_from_slice(&encrypt(&message.to, &header)?);
+                }
+                None => res.push(0),
+            }
+            let mut plaintext = sign(key, &crate::envelope::encode(message));
+            // framing || ratchet header || tag
+            pad(&mut plaintext, res.len() + 40 + 16, padding, max_len);
+            let ciphertext = session.encrypt(&res, &plaintext)?;
+            res.extend_from_slice(&ciphertext);
+        }
+        _ => failure::bail!("Unsupported version"),
+    }
+    Ok(res)
+}
+
+// pads so the whole message, `overhead` included, lands on a padding boundary without
+// exceeding `max_len`
+fn pad(plaintext: &mut Vec<u8>, overhead: usize, padding: Padding, max_len: u64) {
+    plaintext.push(0x80);
+    let len = (plaintext.len() + overhead) as u64;
+    let padded = padding.padded_len(len).min(max_len).max(len);
+    plaintext.resize(plaintext.len() + (padded - len) as usize, 0);
+}
+
+fn unpad(plaintext: &[u8]) -> Result<&[u8], Error> {
+    match plaintext.iter().rposition(|b| *b != 0) {
+        Some(end) if plaintext[end] == 0x80 => Ok(&plaintext[..end]),
+        _ => failure::bail!("Invalid padding"),
     }
-    let pubkey = PublicKey::from_bytes(
-        bytes
-            .get(1..33)
-            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
-    )?;
-    let sig = Signature::try_from(
-        bytes
-            .get(33..97)
-            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
-    )?;
-    let payload = bytes
-        .get(97..)
-        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
-    pubkey.verify(&payload, &sig)?;
+}
+
+fn parse_v0(bytes: &[u8]) -> Result<NewInboundMessage, Error> {
+    let (from, payload) = verify(bytes)?;
+    let mut nonce = [0; 16];
+    nonce.clone_from_slice(&bytes[32..48]);
     let mut time_buf = [0; 8];
-    time_buf.clone_from_slice(
-        payload
-            .get(..8)
-            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?,
-    );
+    time_buf.clone_from_slice(payload.get(..8).ok_or_else(eof)?);
     Ok(NewInboundMessage {
-        from: pubkey,
+        from,
+        nonce,
+        uuid: Uuid::from_bytes(nonce),
+        reply_to: None,
+        group: None,
         time: i64::from_be_bytes(time_buf),
-        content: String::from_utf8(
-            payload
-                .get(8..)
-                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
-                .to_vec(),
-        )?,
+        ttl: None,
+        content: Content::Text(String::from_utf8(
+            payload.get(8..).ok_or_else(eof)?.to_vec(),
+        )?),
     })
 }
 
-pub fn encode(key: &ExpandedSecretKey, message: &NewOutboundMessage) -> Result<Vec<u8>, Error> {
-    let mut res = Vec::with_capacity(101 + message.content.as_bytes().len());
+fn parse_v1(key: &ExpandedSecretKey, bytes: &[u8]) -> Result<Inbound, Error> {
+    let (from, payload) = verify(bytes)?;
+    crate::envelope::parse(from, &PublicKey::from(key), payload)
+}
+
+fn verify(bytes: &[u8]) -> Result<(PublicKey, &[u8]), Error> {
+    let pubkey = PublicKey::from_bytes(bytes.get(..32).ok_or_else(eof)?)?;
+    let sig = Signature::try_from(bytes.get(32..96).ok_or_else(eof)?)?;
+    let payload = bytes.get(96..).ok_or_else(eof)?;
+    pubkey.verify(payload, &sig)?;
+    Ok((pubkey, payload))
+}
+
+fn sign(key: &ExpandedSecretKey, payload: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(96 + payload.len());
     let pubkey = PublicKey::from(key);
-    res.push(0);
     res.extend_from_slice(pubkey.as_bytes());
-    res.extend_from_slice(&[0; 64]);
-    res.extend_from_slice(&i64::to_be_bytes(message.time));
-    res.extend_from_slice(&message.content.as_bytes());
-    let sig = key.sign(&res[97..], &pubkey);
-    res[33..97].clone_from_slice(&sig.to_bytes());
+    res.extend_from_slice(&key.sign(payload, &pubkey).to_bytes());
+    res.extend_from_slice(payload);
+    res
+}
+
+/// Maps an ed25519 onion key onto the birationally equivalent x25519 key.
+pub fn x25519_pubkey(pubkey: &PublicKey) -> Result<MontgomeryPoint, Error> {
+    Ok(CompressedEdwardsY::from_slice(pubkey.as_bytes())
+        .decompress()
+        .ok_or_else(|| failure::format_err!("invalid pubkey"))?
+        .to_montgomery())
+}
+
+/// The lower half of an expanded ed25519 key is the (already clamped) x25519 scalar.
+pub fn x25519_scalar(key: &ExpandedSecretKey) -> Scalar {
+    let mut bits = [0; 32];
+    bits.clone_from_slice(&key.to_bytes()[..32]);
+    Scalar::from_bits(bits)
+}
+
+fn cipher(
+    shared: &MontgomeryPoint,
+    ephemeral: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> Result<ChaCha20Poly1305, Error> {
+    if shared.as_bytes() == &[0; 32] {
+        failure::bail!("invalid ephemeral key");
+    }
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"cups wire v1");
+    hasher.update(shared.as_bytes());
+    hasher.update(ephemeral.as_bytes());
+    hasher.update(recipient.as_bytes());
+    Ok(ChaCha20Poly1305::new(&hasher.finalize()))
+}
 
+// every message is sealed under a fresh ephemeral key, so a fixed nonce never repeats for a key
+fn encrypt(to: &PublicKey, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let recipient = x25519_pubkey(to)?;
+    let secret = Scalar::random(&mut rand::rngs::OsRng);
+    let ephemeral = X25519_BASEPOINT * secret;
+    let ciphertext = cipher(&(recipient * secret), &ephemeral, &recipient)?
+        .encrypt(&Nonce::from([0; 12]), plaintext)
+        .map_err(|_| failure::format_err!("encryption failed"))?;
+    let mut res = Vec::with_capacity(32 + ciphertext.len());
+    res.extend_from_slice(ephemeral.as_bytes());
+    res.extend_from_slice(&ciphertext);
     Ok(res)
 }
+
+fn decrypt(key: &ExpandedSecretKey, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut ephemeral = [0; 32];
+    ephemeral.clone_from_slice(bytes.get(..32).ok_or_else(eof)?);
+    let ephemeral = MontgomeryPoint(ephemeral);
+    let secret = x25519_scalar(key);
+    let recipient = X25519_BASEPOINT * secret;
+    cipher(&(ephemeral * secret), &ephemeral, &recipient)?
+        .decrypt(&Nonce::from([0; 12]), bytes.get(32..).ok_or_else(eof)?)
+        .map_err(|_| failure::format_err!("decryption failed"))
+}
+
+// a receipt is the recipient's signature over the sender and nonce of the message it accepted
+pub fn encode_receipt(key: &ExpandedSecretKey, from: &PublicKey, nonce: &[u8; 16]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(65);
+    res.push(0);
+    res.extend_from_slice(
+        &key.sign(&receipt_payload(from, nonce), &PublicKey::from(key))
+            .to_bytes(),
+    );
+    res
+}
+
+pub fn verify_receipt(
+    signer: &PublicKey,
+    from: &PublicKey,
+    nonce: &[u8; 16],
+    bytes: &[u8],
+) -> Result<(), Error> {
+    if bytes.first().ok_or_else(eof)? != &0 {
+        failure::bail!("Unsupported version");
+    }
+    let sig = Signature::try_from(bytes.get(1..65).ok_or_else(eof)?)?;
+    signer.verify(&receipt_payload(from, nonce), &sig)?;
+    Ok(())
+}
+
+fn receipt_payload(from: &PublicKey, nonce: &[u8; 16]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(60);
+    res.extend_from_slice(b"cups receipt");
+    res.extend_from_slice(from.as_bytes());
+    res.extend_from_slice(nonce);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: &[Padding] = &[Padding::None, Padding::PowerOfTwo, Padding::Padme];
+
+    fn keypair() -> (ExpandedSecretKey, PublicKey) {
+        let key =
+            ExpandedSecretKey::from(&ed25519_dalek::SecretKey::generate(&mut rand::rngs::OsRng));
+        let pubkey = PublicKey::from(&key);
+        (key, pubkey)
+    }
+
+    fn text(to: PublicKey, text: &str) -> NewOutboundMessage {
+        NewOutboundMessage {
+            tracking_id: None,
+            to,
+            nonce: rand::random(),
+            uuid: Uuid::new_v4(),
+            reply_to: Some(Uuid::new_v4()),
+            group: None,
+            time: 1_600_000_000,
+            ttl: None,
+            content: Content::Text(text.to_owned()),
+        }
+    }
+
+    fn assert_received(inbound: Inbound, from: &PublicKey, sent: &NewOutboundMessage) {
+        let msg = match inbound {
+            Inbound::Message(msg) => msg,
+            Inbound::Unsupported { .. } => panic!("parsed as unsupported"),
+        };
+        assert_eq!(&msg.from, from);
+        assert_eq!(msg.nonce, sent.nonce);
+        assert_eq!(msg.uuid, sent.uuid);
+        assert_eq!(msg.reply_to, sent.reply_to);
+        assert_eq!(msg.time, sent.time);
+        match (msg.content, &sent.content) {
+            (Content::Text(received), Content::Text(sent)) => assert_eq!(&received, sent),
+            _ => panic!("content changed kind"),
+        }
+    }
+
+    #[test]
+    fn padded_len() {
+        for len in 0..5000 {
+            assert_eq!(Padding::None.padded_len(len), len);
+            let padded = Padding::PowerOfTwo.padded_len(len);
+            assert!(padded.is_power_of_two());
+            assert!(padded >= len.max(MIN_PADDED_LEN));
+            assert!(padded < 2 * len.max(MIN_PADDED_LEN));
+            let padded = Padding::Padme.padded_len(len);
+            let len = len.max(MIN_PADDED_LEN);
+            assert!(padded >= len);
+            assert!((padded - len) as f64 <= len as f64 * 0.12);
+        }
+        assert_eq!(Padding::PowerOfTwo.padded_len(300), 512);
+        assert_eq!(Padding::Padme.padded_len(1000), 1024);
+        assert_eq!(Padding::Padme.padded_len(1025), 1088);
+    }
+
+    #[test]
+    fn pad_round_trip() {
+        for &padding in MODES {
+            for len in 0..600 {
+                let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let mut padded = data.clone();
+                pad(&mut padded, 49, padding, u64::MAX);
+                assert_eq!(
+                    (padded.len() + 49) as u64,
+                    padding.padded_len(len as u64 + 1 + 49)
+                );
+                assert_eq!(unpad(&padded).unwrap(), &data[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn pad_respects_max_len() {
+        let mut padded = vec![1; 300];
+        pad(&mut padded, 0, Padding::PowerOfTwo, 400);
+        assert_eq!(padded.len(), 400);
+        let mut padded = vec![1; 300];
+        pad(&mut padded, 0, Padding::PowerOfTwo, 100);
+        assert_eq!(padded.len(), 301);
+        assert_eq!(unpad(&padded).unwrap(), &[1; 300][..]);
+    }
+
+    #[test]
+    fn unpad_rejects_bad_marker() {
+        assert!(unpad(&[]).is_err());
+        assert!(unpad(&[0, 0, 0]).is_err());
+        assert!(unpad(&[1, 2, 3]).is_err());
+        assert!(unpad(&[1, 2, 3, 0, 0]).is_err());
+        assert!(unpad(&[1, 2, 0x81, 0, 0]).is_err());
+        assert!(unpad(&[0x80]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let (alice, alice_pub) = keypair();
+        let (bob, bob_pub) = keypair();
+        for &padding in MODES {
+            let msg = text(bob_pub, "hello over v1");
+            let bytes = encode(&alice, 1, &msg, None, padding, u64::MAX).unwrap();
+            assert_eq!(bytes[0], 1);
+            if padding == Padding::PowerOfTwo {
+                assert!(bytes.len().is_power_of_two());
+            }
+            assert_received(parse(&bob, &bytes, None).unwrap(), &alice_pub, &msg);
+            // sealed to bob alone
+            assert!(parse(&alice, &bytes, None).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_payload_signed_for_another_recipient() {
+        let (alice, _) = keypair();
+        let (mallory, mallory_pub) = keypair();
+        let (bob, bob_pub) = keypair();
+        let bytes = encode(
+            &alice,
+            1,
+            &text(mallory_pub, "for mallory"),
+            None,
+            Padding::None,
+            u64::MAX,
+        )
+        .unwrap();
+        let signed = unpad(&decrypt(&mallory, &bytes[1..]).unwrap())
+            .unwrap()
+            .to_vec();
+        let forward = |mut signed: Vec<u8>| {
+            pad(&mut signed, 1 + 32 + 16, Padding::None, u64::MAX);
+            let mut res = vec![1];
+            res.extend_from_slice(&encrypt(&bob_pub, &signed).unwrap());
+            res
+        };
+        // mallory passes alice's signed frame on to bob as it is
+        assert_eq!(
+            parse(&bob, &forward(signed.clone()), None)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Message addressed to another recipient"
+        );
+        // or readdressed, which breaks alice's signature: pubkey || sig || version || TO || len
+        let to = 96 + 1 + 1 + 4;
+        assert_eq!(&signed[to..to + 32], mallory_pub.as_bytes());
+        let mut readdressed = signed;
+        readdressed[to..to + 32].clone_from_slice(bob_pub.as_bytes());
+        assert!(parse(&bob, &forward(readdressed), None).is_err());
+    }
+
+    #[test]
+    fn rejects_forged_receipts() {
+        let (peer, peer_pub) = keypair();
+        let (sender, sender_pub) = keypair();
+        let nonce = rand::random();
+        let receipt = encode_receipt(&peer, &sender_pub, &nonce);
+        assert!(verify_receipt(&peer_pub, &sender_pub, &nonce, &receipt).is_ok());
+        // signed by another key
+        let forged = encode_receipt(&sender, &sender_pub, &nonce);
+        assert!(verify_receipt(&peer_pub, &sender_pub, &nonce, &forged).is_err());
+        // for another message, or another sender's
+        assert!(verify_receipt(&peer_pub, &sender_pub, &rand::random(), &receipt).is_err());
+        assert!(verify_receipt(&peer_pub, &peer_pub, &nonce, &receipt).is_err());
+        let mut tampered = receipt.clone();
+        tampered[10] ^= 1;
+        assert!(verify_receipt(&peer_pub, &sender_pub, &nonce, &tampered).is_err());
+        let mut unknown_version = receipt.clone();
+        unknown_version[0] = 1;
+        assert!(verify_receipt(&peer_pub, &sender_pub, &nonce, &unknown_version).is_err());
+        assert!(verify_receipt(&peer_pub, &sender_pub, &nonce, &receipt[..64]).is_err());
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let (alice, alice_pub) = keypair();
+        let (bob, bob_pub) = keypair();
+        let prekey = Scalar::random(&mut rand::rngs::OsRng);
+        let mut alice_session =
+            Session::initiate(&alice, bob_pub, X25519_BASEPOINT * prekey).unwrap();
+
+        let msg = text(bob_pub, "hello over v2");
+        let bytes = encode(
+            &alice,
+            2,
+            &msg,
+            Some(&mut alice_session),
+            Padding::Padme,
+            u64::MAX,
+        )
+        .unwrap();
+        let (id, init) = parse_session(&bob, &bytes).unwrap().unwrap();
+        assert_eq!(id, alice_session.id);
+        let mut bob_session = Session::respond(&bob, id, &init.unwrap(), prekey).unwrap();
+        assert_received(
+            parse(&bob, &bytes, Some(&mut bob_session)).unwrap(),
+            &alice_pub,
+            &msg,
+        );
+
+        let reply = text(alice_pub, "and back");
+        let bytes = encode(
+            &bob,
+            2,
+            &reply,
+            Some(&mut bob_session),
+            Padding::Padme,
+            u64::MAX,
+        )
+        .unwrap();
+        assert!(parse_session(&alice, &bytes).unwrap().unwrap().1.is_none());
+        assert_received(
+            parse(&alice, &bytes, Some(&mut alice_session)).unwrap(),
+            &bob_pub,
+            &reply,
+        );
+    }
+
+    struct Peer {
+        key: ExpandedSecretKey,
+        pubkey: PublicKey,
+        session: Session,
+    }
+
+    impl Peer {
+        fn send(&mut self, to: &Peer, body: &str) -> (NewOutboundMessage, Vec<u8>) {
+            let msg = text(to.pubkey, body);
+            let bytes = encode(
+                &self.key,
+                2,
+                &msg,
+                Some(&mut self.session),
+                Padding::None,
+                u64::MAX,
+            )
+            .unwrap();
+            (msg, bytes)
+        }
+
+        fn receive(&mut self, bytes: &[u8]) -> Result<Inbound, Error> {
+            parse(&self.key, bytes, Some(&mut self.session))
+        }
+    }
+
+    // alice starts a session from bob's prekey, and bob picks it up from her first message
+    fn handshake() -> (Peer, Peer) {
+        let (alice_key, alice_pub) = keypair();
+        let (bob_key, bob_pub) = keypair();
+        let prekey = Scalar::random(&mut rand::rngs::OsRng);
+        let mut alice = Peer {
+            session: Session::initiate(&alice_key, bob_pub, X25519_BASEPOINT * prekey).unwrap(),
+            key: alice_key,
+            pubkey: alice_pub,
+        };
+        let msg = text(bob_pub, "hello");
+        let bytes = encode(
+            &alice.key,
+            2,
+            &msg,
+            Some(&mut alice.session),
+            Padding::None,
+            u64::MAX,
+        )
+        .unwrap();
+        let (id, init) = parse_session(&bob_key, &bytes).unwrap().unwrap();
+        let mut bob = Peer {
+            session: Session::respond(&bob_key, id, &init.unwrap(), prekey).unwrap(),
+            key: bob_key,
+            pubkey: bob_pub,
+        };
+        assert_received(bob.receive(&bytes).unwrap(), &alice.pubkey, &msg);
+        (alice, bob)
+    }
+
+    #[test]
+    fn ratchet_handshake() {
+        let (mut alice, mut bob) = handshake();
+        // alice repeats the handshake, sealed to bob, until he answers
+        let (msg, bytes) = alice.send(&bob, "again");
+        assert!(parse_session(&bob.key, &bytes)
+            .unwrap()
+            .unwrap()
+            .1
+            .is_some());
+        let (stranger, _) = keypair();
+        assert!(parse_session(&stranger, &bytes).is_err());
+        assert_received(bob.receive(&bytes).unwrap(), &alice.pubkey, &msg);
+        let (msg, bytes) = bob.send(&alice, "hi");
+        assert_received(alice.receive(&bytes).unwrap(), &bob.pubkey, &msg);
+        assert!(alice.session.init.is_none());
+        let (msg, bytes) = alice.send(&bob, "no more handshake");
+        assert!(parse_session(&bob.key, &bytes)
+            .unwrap()
+            .unwrap()
+            .1
+            .is_none());
+        assert_received(bob.receive(&bytes).unwrap(), &alice.pubkey, &msg);
+    }
+
+    #[test]
+    fn ratchet_turns() {
+        let (mut alice, mut bob) = handshake();
+        for turn in 0..6 {
+            for i in 0..=turn {
+                let (msg, bytes) = alice.send(&bob, &format!("alice {} {}", turn, i));
+                assert_received(bob.receive(&bytes).unwrap(), &alice.pubkey, &msg);
+            }
+            for i in 0..3 {
+                let (msg, bytes) = bob.send(&alice, &format!("bob {} {}", turn, i));
+                assert_received(alice.receive(&bytes).unwrap(), &bob.pubkey, &msg);
+            }
+        }
+    }
+
+    #[test]
+    fn ratchet_out_of_order() {
+        let (mut alice, mut bob) = handshake();
+        let sent: Vec<_> = (0..4)
+            .map(|i| alice.send(&bob, &format!("first chain {}", i)))
+            .collect();
+        for &i in &[2, 0, 3] {
+            assert_received(bob.receive(&sent[i].1).unwrap(), &alice.pubkey, &sent[i].0);
+        }
+        let (msg, bytes) = bob.send(&alice, "turn");
+        assert_received(alice.receive(&bytes).unwrap(), &bob.pubkey, &msg);
+        // the new chain arrives before the rest of the old one
+        let (msg, bytes) = alice.send(&bob, "second chain");
+        assert_received(bob.receive(&bytes).unwrap(), &alice.pubkey, &msg);
+        assert_received(bob.receive(&sent[1].1).unwrap(), &alice.pubkey, &sent[1].0);
+    }
+
+    #[test]
+    fn ratchet_rejects_too_many_skipped() {
+        let (mut alice, mut bob) = handshake();
+        let sent: Vec<_> = (0..crate::session::MAX_SKIP + 2)
+            .map(|i| alice.send(&bob, &i.to_string()))
+            .collect();
+        let before = bob.session.encode();
+        let (msg, bytes) = &sent[sent.len() - 1];
+        assert!(bob.receive(bytes).is_err());
+        assert_eq!(bob.session.encode(), before);
+        // exactly MAX_SKIP messages may be skipped
+        let (msg_before, bytes_before) = &sent[sent.len() - 2];
+        assert_received(
+            bob.receive(bytes_before).unwrap(),
+            &alice.pubkey,
+            msg_before,
+        );
+        assert_received(bob.receive(bytes).unwrap(), &alice.pubkey, msg);
+    }
+
+    #[test]
+    fn ratchet_rejects_replay() {
+        let (mut alice, mut bob) = handshake();
+        let (first, second) = (alice.send(&bob, "first"), alice.send(&bob, "second"));
+        assert_received(bob.receive(&second.1).unwrap(), &alice.pubkey, &second.0);
+        assert!(bob.receive(&second.1).is_err());
+        // a skipped message key is only good once too
+        assert_received(bob.receive(&first.1).unwrap(), &alice.pubkey, &first.0);
+        assert!(bob.receive(&first.1).is_err());
+        let (msg, bytes) = alice.send(&bob, "still in sync");
+        assert_received(bob.receive(&bytes).unwrap(), &alice.pubkey, &msg);
+    }
+}